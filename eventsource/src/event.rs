@@ -2,9 +2,10 @@ use std::fmt;
 use std::time::Duration;
 
 /// A single Server-Sent Event.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Event {
-    /// Corresponds to the `id` field.
+    /// Corresponds to the `id` field. Events parsed by `Parser` carry the last id of the stream,
+    /// as in the `lastEventId` of browsers.
     pub id: Option<String>,
     /// Corresponds to the `event` field.
     pub event_type: Option<String>,
@@ -25,7 +26,8 @@ pub enum ParseResult {
 
 /// Parse a single line of an event-stream.
 ///
-/// The line may end with a newline.
+/// The line may end with a newline. Prefer `Parser`, which also handles line endings, the byte
+/// order mark and empty events as specified.
 ///
 /// You will have to call this function multiple times until it returns `ParseResult::Dispatch`.
 /// Make sure to clear the event struct for the next line, then.
@@ -78,6 +80,167 @@ pub fn parse_event_line(line: &str, event: &mut Event) -> ParseResult {
     }
 }
 
+/// What `Parser` found in the stream.
+#[derive(Debug, PartialEq)]
+pub enum Parsed {
+    /// A complete event.
+    Event(Event),
+    /// A new reconnection time.
+    Retry(Duration),
+}
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// An incremental parser of a `text/event-stream` as specified by the WHATWG, fed with the bytes
+/// of the stream as they arrive.
+///
+/// - Lines end with `\r\n`, `\n` or `\r`, even if split across chunks.
+/// - A byte order mark at the start of the stream is skipped.
+/// - The final newline of `data` is removed, and events without data aren't dispatched.
+/// - `id` values containing NUL are ignored, the last id is kept for the following events.
+/// - `retry` values that aren't only digits are ignored.
+/// - An event that isn't terminated by an empty line when the stream ends is discarded.
+///
+/// # Examples
+///
+/// ```
+/// # use eventsource::event::{Parser, Parsed};
+/// let mut parser = Parser::new();
+/// assert_eq!(parser.feed(b"data: foo\r\ndata: ba"), vec![]);
+/// match &parser.feed(b"r\r\n\r\n")[..] {
+///     [Parsed::Event(event)] => assert_eq!(event.data, "foo\nbar"),
+///     _ => panic!(),
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Parser {
+    line: Vec<u8>,
+    /// Whether the last chunk ended with `\r`, so that a `\n` starting the next one belongs to it.
+    after_cr: bool,
+    first_line: bool,
+    event_type: Option<String>,
+    data: String,
+    last_event_id: Option<String>,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::resuming(None)
+    }
+
+    /// Creates a parser for a stream resumed after the event with the given id.
+    pub fn resuming(last_event_id: Option<String>) -> Parser {
+        Parser {
+            line: Vec::new(),
+            after_cr: false,
+            first_line: true,
+            event_type: None,
+            data: String::new(),
+            last_event_id,
+        }
+    }
+
+    /// Prepares for a new stream after a reconnect: a partial event is discarded and a byte
+    /// order mark is expected again. The last id is kept.
+    pub fn reset(&mut self) {
+        self.line.clear();
+        self.after_cr = false;
+        self.first_line = true;
+        self.event_type = None;
+        self.data.clear();
+    }
+
+    /// The last id received, to be sent as `Last-Event-ID` when reconnecting.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_ref().map(|id| &id[..])
+    }
+
+    /// Parses the next bytes of the stream, returning what was completed by them.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Parsed> {
+        let mut parsed = Vec::new();
+        let mut bytes = bytes;
+        if self.after_cr && !bytes.is_empty() {
+            self.after_cr = false;
+            if bytes[0] == b'\n' {
+                bytes = &bytes[1..];
+            }
+        }
+
+        while let Some(pos) = bytes.iter().position(|&b| b == b'\r' || b == b'\n') {
+            self.line.extend_from_slice(&bytes[..pos]);
+            let line = ::std::mem::replace(&mut self.line, Vec::new());
+            parsed.extend(self.process_line(&line));
+
+            let crlf = bytes[pos] == b'\r' && bytes.get(pos + 1) == Some(&b'\n');
+            if bytes[pos] == b'\r' && pos + 1 == bytes.len() {
+                self.after_cr = true;
+            }
+            bytes = &bytes[pos + if crlf { 2 } else { 1 }..];
+        }
+        self.line.extend_from_slice(bytes);
+        parsed
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<Parsed> {
+        let line = if self.first_line && line.starts_with(BOM) { &line[BOM.len()..] } else { line };
+        self.first_line = false;
+        let line = String::from_utf8_lossy(line);
+
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            // A comment
+            return None;
+        }
+        let (field, value) = match line.find(':') {
+            Some(pos) => {
+                let value = &line[pos + 1..];
+                (&line[..pos], if value.starts_with(' ') { &value[1..] } else { value })
+            }
+            None => (&line[..], ""),
+        };
+
+        match field {
+            "event" => self.event_type = Some(value.to_string()),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => {
+                self.last_event_id = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(retry) = value.parse::<u64>() {
+                    return Some(Parsed::Retry(Duration::from_millis(retry)));
+                }
+            }
+            _ => (), // ignored
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Parsed> {
+        let event_type = self.event_type.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut data = ::std::mem::replace(&mut self.data, String::new());
+        data.pop();
+        Some(Parsed::Event(Event {
+            id: self.last_event_id.clone(),
+            event_type,
+            data,
+        }))
+    }
+}
+
 impl Event {
     /// Creates an empty event.
     pub fn new() -> Event {
@@ -144,4 +307,112 @@ mod tests {
             "data: hello\ndata: \ndata: world\n",
             Event { id: None, event_type: None, data: "hello\n\nworld".to_string() }.to_string());
     }
+
+    fn event(id: Option<&str>, event_type: Option<&str>, data: &str) -> Parsed {
+        Parsed::Event(Event {
+            id: id.map(str::to_string),
+            event_type: event_type.map(str::to_string),
+            data: data.to_string(),
+        })
+    }
+
+    fn parse(stream: &[u8]) -> Vec<Parsed> {
+        Parser::new().feed(stream)
+    }
+
+    /// Parses the stream byte by byte, as if every byte arrived in a chunk of its own.
+    fn parse_bytewise(stream: &[u8]) -> Vec<Parsed> {
+        let mut parser = Parser::new();
+        stream.iter().flat_map(|b| parser.feed(&[*b])).collect()
+    }
+
+    #[test]
+    fn line_endings() {
+        let expected = vec![event(None, Some("a"), "1\n2"), event(None, None, "3")];
+        for stream in &[
+            &b"event: a\ndata: 1\ndata: 2\n\ndata: 3\n\n"[..],
+            &b"event: a\r\ndata: 1\r\ndata: 2\r\n\r\ndata: 3\r\n\r\n"[..],
+            &b"event: a\rdata: 1\rdata: 2\r\rdata: 3\r\r"[..],
+            &b"event: a\r\ndata: 1\rdata: 2\n\r\ndata: 3\r\n\n"[..],
+        ] {
+            assert_eq!(parse(stream), expected);
+            assert_eq!(parse_bytewise(stream), expected);
+        }
+    }
+
+    #[test]
+    fn crlf_split_across_chunks() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.feed(b"data: 1\r"), vec![]);
+        assert_eq!(parser.feed(b"\n\r"), vec![event(None, None, "1")]);
+        assert_eq!(parser.feed(b"\ndata: 2\r"), vec![]);
+        assert_eq!(parser.feed(b"\r"), vec![event(None, None, "2")]);
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        assert_eq!(parse(b"\xef\xbb\xbfdata: 1\n\n"), vec![event(None, None, "1")]);
+        assert_eq!(parse_bytewise(b"\xef\xbb\xbfdata: 1\n\n"), vec![event(None, None, "1")]);
+        // Only at the start of the stream, elsewhere it's part of the field name
+        assert_eq!(parse(b"\xef\xbb\xbf\xef\xbb\xbfdata: 1\n\n"), vec![]);
+        assert_eq!(parse(b"data: 1\n\n\xef\xbb\xbfdata: 2\n\n"), vec![event(None, None, "1")]);
+
+        let mut parser = Parser::new();
+        assert_eq!(parser.feed(b"data: 1\n\n").len(), 1);
+        parser.reset();
+        assert_eq!(parser.feed(b"\xef\xbb\xbfdata: 2\n\n"), vec![event(None, None, "2")]);
+    }
+
+    #[test]
+    fn data() {
+        assert_eq!(parse(b"data:1\n\n"), vec![event(None, None, "1")]);
+        assert_eq!(parse(b"data:  1 \n\n"), vec![event(None, None, " 1 ")]);
+        assert_eq!(parse(b"data: a: b\n\n"), vec![event(None, None, "a: b")]);
+        assert_eq!(parse(b"data\n\n"), vec![event(None, None, "")]);
+        assert_eq!(parse(b"data\ndata\n\n"), vec![event(None, None, "\n")]);
+        assert_eq!(parse(b"data: 1\ndata:\ndata: 2\n\n"), vec![event(None, None, "1\n\n2")]);
+        assert_eq!(parse(b"data: \xff\n\n"), vec![event(None, None, "\u{fffd}")]);
+    }
+
+    #[test]
+    fn events_without_data_are_not_dispatched() {
+        assert_eq!(parse(b"event: a\n\ndata: 1\n\n"), vec![event(None, None, "1")]);
+        assert_eq!(parse(b"\n\n\n"), vec![]);
+        assert_eq!(parse(b"id: 1\n\ndata: 2\n\n"), vec![event(Some("1"), None, "2")]);
+    }
+
+    #[test]
+    fn unterminated_event_is_discarded() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.feed(b"data: 1\n\ndata: 2\n"), vec![event(None, None, "1")]);
+        parser.reset();
+        assert_eq!(parser.feed(b"data: 3\n\n"), vec![event(None, None, "3")]);
+    }
+
+    #[test]
+    fn comments_and_unknown_fields() {
+        assert_eq!(parse(b": hello\ndata: 1\n:\nfoo: bar\nDATA: 2\n\n"), vec![event(None, None, "1")]);
+        assert_eq!(parse(b":\n\n"), vec![]);
+    }
+
+    #[test]
+    fn last_event_id() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.feed(b"id: 1\ndata: a\n\ndata: b\n\n"), vec![event(Some("1"), None, "a"), event(Some("1"), None, "b")]);
+        assert_eq!(parser.feed(b"id: 2\0\ndata: c\n\n"), vec![event(Some("1"), None, "c")]);
+        assert_eq!(parser.last_event_id(), Some("1"));
+        parser.reset();
+        assert_eq!(parser.last_event_id(), Some("1"));
+        assert_eq!(parser.feed(b"id\ndata: d\n\n"), vec![event(None, None, "d")]);
+        assert_eq!(parser.last_event_id(), None);
+    }
+
+    #[test]
+    fn retry() {
+        assert_eq!(parse(b"retry: 1500\n"), vec![Parsed::Retry(Duration::from_millis(1500))]);
+        assert_eq!(parse(b"retry:0\n"), vec![Parsed::Retry(Duration::from_millis(0))]);
+        for value in &["", " 1", "+1", "-1", "1.5", "1s", "99999999999999999999999"] {
+            assert_eq!(parse(format!("retry: {}\n", value).as_bytes()), vec![]);
+        }
+    }
 }
@@ -13,20 +13,37 @@
 //! use reqwest::Url;
 //!
 //! fn main() {
-//!     let client = Client::new(Url::parse("http://example.com").unwrap());
+//!     let client = Client::new(Url::parse("http://example.com").unwrap(), None).unwrap();
 //!     for event in client {
 //!         println!("{}", event.unwrap());
 //!     }
 //! }
 //! ```
 //!
+//! With the `with-reqwest-async` feature, `eventsource::reqwest_async` provides the same client as
+//! a futures `Stream`.
+//!
 
 #[macro_use]
 extern crate error_chain;
+#[cfg(feature = "with-reqwest-async")]
+extern crate futures;
+#[cfg(feature = "with-reqwest-async")]
+extern crate tokio_core;
 
 // Generic text/event-stream parsing and serialization.
 pub mod event;
 
+// Backoff between reconnects.
+pub mod reconnect;
+
+// Writing event streams
+pub mod server;
+
 // HTTP interface
 #[cfg(feature = "with-reqwest")]
 pub mod reqwest;
+
+// Asynchronous HTTP interface
+#[cfg(feature = "with-reqwest-async")]
+pub mod reqwest_async;
@@ -0,0 +1,125 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How a client reconnects after the connection is lost or can't be established.
+///
+/// The delay before a reconnect starts at the reconnection time of the stream and doubles with
+/// every consecutive failed attempt, up to `max_delay`. A random part of it, up to `jitter`, is
+/// left out so that clients don't all reconnect at the same time after an outage. The client
+/// gives up after `max_attempts` consecutive failed attempts, or once reconnecting would take
+/// longer than `max_elapsed`.
+///
+/// # Examples
+///
+/// ```
+/// # use eventsource::reconnect::ReconnectPolicy;
+/// # use std::time::Duration;
+/// let policy = ReconnectPolicy {
+///     max_attempts: Some(5),
+///     ..ReconnectPolicy::default()
+/// };
+/// assert_eq!(policy.backoff(Duration::from_secs(1), 3), Duration::from_secs(4));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Consecutive failed attempts after which to give up, `None` for no limit.
+    pub max_attempts: Option<u32>,
+    /// Upper bound of the delay between two attempts.
+    pub max_delay: Duration,
+    /// Time after the first of consecutive failed attempts after which to give up, `None` for no
+    /// limit.
+    pub max_elapsed: Option<Duration>,
+    /// Fraction of the delay, between 0 and 1, that is randomly left out.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: Some(10),
+            max_delay: Duration::from_secs(60),
+            max_elapsed: Some(Duration::from_secs(5 * 60)),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// A policy that never gives up, like a browser.
+    pub fn unlimited() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: None,
+            max_elapsed: None,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    /// The delay before reconnect `attempt`, counted from 1, without jitter.
+    pub fn backoff(&self, retry: Duration, attempt: u32) -> Duration {
+        let factor = 1u32 << (attempt.max(1) - 1).min(31);
+        retry.checked_mul(factor).map_or(self.max_delay, |d| d.min(self.max_delay))
+    }
+
+    /// The delay before reconnect `attempt`, counted from 1, with jitter.
+    pub fn delay(&self, retry: Duration, attempt: u32) -> Duration {
+        let backoff = self.backoff(retry, attempt);
+        let millis = backoff.as_secs() * 1000 + backoff.subsec_nanos() as u64 / 1_000_000;
+        let jitter = self.jitter.max(0.0).min(1.0) * random_fraction();
+        Duration::from_millis(millis - (millis as f64 * jitter) as u64)
+    }
+
+    /// Whether to give up after `attempts` consecutive failed attempts, the first of them
+    /// `elapsed` ago, rather than waiting `delay` for the next one.
+    pub fn gives_up(&self, attempts: u32, elapsed: Duration, delay: Duration) -> bool {
+        self.max_attempts.map_or(false, |max| attempts >= max) ||
+            self.max_elapsed.map_or(false, |max| elapsed + delay > max)
+    }
+}
+
+/// A number in [0, 1), random enough for jitter.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let policy = ReconnectPolicy::default();
+        let retry = Duration::from_secs(5);
+        assert_eq!(policy.backoff(retry, 1), Duration::from_secs(5));
+        assert_eq!(policy.backoff(retry, 2), Duration::from_secs(10));
+        assert_eq!(policy.backoff(retry, 4), Duration::from_secs(40));
+        assert_eq!(policy.backoff(retry, 5), Duration::from_secs(60));
+        assert_eq!(policy.backoff(retry, 1000), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn jitter_shortens_delay() {
+        let policy = ReconnectPolicy::default();
+        let retry = Duration::from_secs(8);
+        for _ in 0..100 {
+            let delay = policy.delay(retry, 1);
+            assert!(delay > Duration::from_secs(4) && delay <= retry);
+        }
+
+        let policy = ReconnectPolicy { jitter: 0.0, ..ReconnectPolicy::default() };
+        assert_eq!(policy.delay(retry, 2), Duration::from_secs(16));
+    }
+
+    #[test]
+    fn gives_up() {
+        let policy = ReconnectPolicy::default();
+        let zero = Duration::from_secs(0);
+        assert!(!policy.gives_up(9, zero, zero));
+        assert!(policy.gives_up(10, zero, zero));
+        assert!(!policy.gives_up(1, Duration::from_secs(200), Duration::from_secs(60)));
+        assert!(policy.gives_up(1, Duration::from_secs(250), Duration::from_secs(60)));
+        assert!(!ReconnectPolicy::unlimited().gives_up(1000, Duration::from_secs(3600), zero));
+    }
+}
@@ -0,0 +1,298 @@
+//! # Asynchronous reqwest-based EventSource client
+//!
+//! The same client as in `eventsource::reqwest`, as a `Stream` on a tokio reactor instead of a
+//! blocking `Iterator`. Build it with `ClientBuilder::build_async`.
+
+extern crate reqwest as reqw;
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::{Handle, Timeout};
+
+use super::event::{Event, Parsed, Parser};
+use super::reconnect::ReconnectPolicy;
+use super::reqwest::{Error, ErrorKind, check_response, request_headers};
+use self::reqw::Url;
+use self::reqw::header::Headers;
+use self::reqw::unstable::async;
+
+enum State {
+    /// About to connect, after the reconnection delay if there was an attempt before.
+    Idle,
+    Waiting(Timeout),
+    Connecting(Box<Future<Item = async::Response, Error = reqw::Error>>),
+    Reading(async::Response),
+    GaveUp,
+}
+
+/// An asynchronous client for a Server-Sent Events endpoint.
+///
+/// The stream yields events, and the errors of requests as they happen; polling it after an
+/// error reconnects according to the `ReconnectPolicy`. Once it gives up, `GaveUp` is returned and
+/// the stream ends. Dropping the client cancels the request in flight and closes the connection.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate eventsource;
+/// # extern crate futures;
+/// # extern crate reqwest;
+/// # extern crate tokio_core;
+/// # use eventsource::reqwest::ClientBuilder;
+/// # use futures::Stream;
+/// # use reqwest::Url;
+/// # use std::time::Duration;
+/// # use tokio_core::reactor::{Core, Timeout};
+/// # fn main() {
+/// let mut core = Core::new().unwrap();
+/// let client = ClientBuilder::new(Url::parse("https://example.com/events").unwrap())
+///     .build_async(&core.handle())
+///     .unwrap();
+/// let first = client.filter(|event| event.event_type.as_ref().map_or(false, |t| t == "put"))
+///     .into_future()
+///     .map(|(event, _)| event)
+///     .map_err(|(err, _)| err);
+/// let timeout = Timeout::new(Duration::from_secs(60), &core.handle()).unwrap();
+/// // Whichever finishes first drops the other one.
+/// match core.run(first.select2(timeout)) {
+///     Ok(futures::future::Either::A((event, _))) => println!("{:?}", event),
+///     _ => println!("timeout or error"),
+/// }
+/// # }
+/// ```
+pub struct Client {
+    client: async::Client,
+    handle: Handle,
+    state: State,
+    url: Url,
+    headers: Headers,
+    parser: Parser,
+    /// Parsed, but not yet returned.
+    pending: VecDeque<Parsed>,
+    last_try: Option<Instant>,
+    /// Consecutive failed attempts, and when the first of them was made.
+    failures: u32,
+    first_failure: Option<Instant>,
+
+    /// Reconnection time. Note that the reconnection time can be changed by the event stream, so
+    /// changing this may not make a difference.
+    pub retry: Duration,
+    /// How to back off and when to give up reconnecting.
+    pub reconnect: ReconnectPolicy,
+}
+
+impl Client {
+    pub(crate) fn new(client: async::Client, handle: &Handle, url: Url, headers: Headers, parser: Parser, retry: Duration, reconnect: ReconnectPolicy) -> Client {
+        Client {
+            client,
+            handle: handle.clone(),
+            state: State::Idle,
+            url,
+            headers,
+            parser,
+            pending: VecDeque::new(),
+            last_try: None,
+            failures: 0,
+            first_failure: None,
+            retry,
+            reconnect,
+        }
+    }
+
+    fn failed(&mut self) {
+        self.state = State::Idle;
+        self.last_try = Some(Instant::now());
+        self.failures += 1;
+        if self.first_failure.is_none() {
+            self.first_failure = Some(Instant::now());
+        }
+    }
+
+    fn connect(&mut self) {
+        self.last_try = Some(Instant::now());
+        let pending = self.client.get(self.url.clone())
+            .headers(request_headers(&self.headers, &self.parser))
+            .send();
+        self.state = State::Connecting(Box::new(pending));
+    }
+}
+
+impl Stream for Client {
+    type Item = Event;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Event>, Error> {
+        loop {
+            while let Some(parsed) = self.pending.pop_front() {
+                match parsed {
+                    Parsed::Event(event) => {
+                        self.failures = 0;
+                        self.first_failure = None;
+                        return Ok(Async::Ready(Some(event)));
+                    }
+                    Parsed::Retry(retry) => self.retry = retry,
+                }
+            }
+
+            match self.state {
+                State::Idle => {
+                    if self.last_try.is_none() {
+                        self.connect();
+                        continue;
+                    }
+                    let delay = self.reconnect.delay(self.retry, self.failures);
+                    let elapsed = self.first_failure.map_or(Duration::from_secs(0), |f| f.elapsed());
+                    if self.reconnect.gives_up(self.failures, elapsed, delay) {
+                        self.state = State::GaveUp;
+                        return Err(ErrorKind::GaveUp(self.failures).into());
+                    }
+                    let wait = delay.checked_sub(self.last_try.unwrap().elapsed()).unwrap_or_default();
+                    self.state = State::Waiting(Timeout::new(wait, &self.handle)?);
+                }
+                State::Waiting(ref mut timeout) => {
+                    if let Async::NotReady = timeout.poll()? {
+                        return Ok(Async::NotReady);
+                    }
+                    self.connect();
+                }
+                State::Connecting(ref mut pending) => {
+                    match pending.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(res)) => {
+                            if let Err(err) = check_response(res.status(), res.headers()) {
+                                self.failed();
+                                return Err(err);
+                            }
+                            self.parser.reset();
+                            self.state = State::Reading(res);
+                        }
+                        Err(err) => {
+                            self.failed();
+                            return Err(err.into());
+                        }
+                    }
+                }
+                State::Reading(ref mut res) => {
+                    match res.body_mut().poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(Some(chunk))) => {
+                            self.pending.extend(self.parser.feed(&chunk));
+                            continue;
+                        }
+                        // EOF or a stream error, retry after the delay
+                        Ok(Async::Ready(None)) | Err(_) => (),
+                    }
+                    self.failed();
+                }
+                State::GaveUp => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event::Event;
+    use futures::future;
+    use reconnect::ReconnectPolicy;
+    use reqwest::ClientBuilder;
+    use server::handshake;
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    fn event(id: &str, data: &str) -> Event {
+        Event {
+            id: Some(id.to_string()),
+            event_type: None,
+            data: data.to_string(),
+        }
+    }
+
+    fn policy(max_attempts: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: Some(max_attempts),
+            jitter: 0.0,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    fn next(core: &mut Core, client: &mut Client) -> Result<Option<Event>, Error> {
+        core.run(client.by_ref().into_future()).map(|(event, _)| event).map_err(|(e, _)| e)
+    }
+
+    /// Polls the client until it waits in the `expected` state, failing if it yields anything
+    /// before.
+    fn poll_until(core: &mut Core, client: &mut Client, expected: fn(&State) -> bool) {
+        core.run(future::poll_fn(|| -> Poll<(), ()> {
+            match client.poll() {
+                Ok(Async::NotReady) if expected(&client.state) => Ok(Async::Ready(())),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Ok(Async::Ready(item)) => panic!("unexpected item: {:?}", item),
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        })).unwrap();
+    }
+
+    #[test]
+    fn reconnects_after_eof() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/events", listener.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let (first, mut writer) = handshake(listener.accept().unwrap().0).unwrap();
+            writer.retry(Duration::from_millis(100)).unwrap();
+            writer.write_event(&event("1", "a")).unwrap();
+            drop(writer);
+
+            let (second, mut writer) = handshake(listener.accept().unwrap().0).unwrap();
+            writer.write_event(&event("2", "b")).unwrap();
+            (first, second)
+        });
+
+        let mut core = Core::new().unwrap();
+        let mut client = ClientBuilder::new(url).reconnect(policy(3)).build_async(&core.handle()).unwrap();
+        assert!(matches!(client.state, State::Idle));
+
+        poll_until(&mut core, &mut client, |s| matches!(*s, State::Connecting(_)));
+        assert_eq!(next(&mut core, &mut client).unwrap(), Some(event("1", "a")));
+        assert!(matches!(client.state, State::Reading(_)));
+
+        // The server closed the stream, so the client waits for the retry time it was sent.
+        poll_until(&mut core, &mut client, |s| matches!(*s, State::Waiting(_)));
+        assert_eq!((client.failures, client.retry), (1, Duration::from_millis(100)));
+        poll_until(&mut core, &mut client, |s| matches!(*s, State::Connecting(_)));
+        assert_eq!(next(&mut core, &mut client).unwrap(), Some(event("2", "b")));
+        assert!(matches!(client.state, State::Reading(_)));
+        assert_eq!(client.failures, 0);
+
+        let (first, second) = server.join().unwrap();
+        assert_eq!(first.last_event_id, None);
+        assert_eq!(second.last_event_id, Some("1".to_string()));
+    }
+
+    #[test]
+    fn gives_up() {
+        // Nothing listens on the port once the listener is dropped.
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let url = Url::parse(&format!("http://{}/events", addr)).unwrap();
+
+        let mut core = Core::new().unwrap();
+        let mut client = ClientBuilder::new(url).reconnect(policy(2)).build_async(&core.handle()).unwrap();
+        client.retry = Duration::from_millis(10);
+
+        for attempt in 1..3 {
+            assert!(next(&mut core, &mut client).is_err());
+            assert!(matches!(client.state, State::Idle));
+            assert_eq!(client.failures, attempt);
+        }
+        match next(&mut core, &mut client) {
+            Err(e) => assert!(matches!(*e.kind(), ErrorKind::GaveUp(2)), "unexpected error: {}", e),
+            Ok(event) => panic!("unexpected event: {:?}", event),
+        }
+        assert!(matches!(client.state, State::GaveUp));
+        assert_eq!(next(&mut core, &mut client).unwrap(), None);
+    }
+}
@@ -0,0 +1,395 @@
+//! # Server-side support
+//!
+//! Writing `text/event-stream` responses, for relays and mock backends. `handshake` answers a
+//! plain HTTP request on a connection, after which events are written with an `EventWriter`.
+//! Events can be kept in a `ReplayBuffer` to resume streams after `Last-Event-ID`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use eventsource::event::Event;
+//! # use eventsource::server::{handshake, ReplayBuffer};
+//! # use std::net::TcpListener;
+//! # use std::time::Duration;
+//! let mut replay = ReplayBuffer::new(100);
+//! replay.push(Event { id: Some("1".to_string()), event_type: None, data: "hello".to_string() });
+//!
+//! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+//! for stream in listener.incoming() {
+//!     let (request, mut writer) = handshake(stream.unwrap()).unwrap();
+//!     writer.retry(Duration::from_secs(1)).unwrap();
+//!     writer.replay(&replay, request.last_event_id.as_ref().map(|id| &id[..])).unwrap();
+//!     writer.keep_alive().unwrap();
+//! }
+//! ```
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use super::event::Event;
+
+/// Writes events to a connection, flushing after each of them.
+///
+/// Unlike the `Display` implementation of `Event`, the data is written so that the client parses
+/// exactly the same data, including empty and trailing lines. Carriage returns in the data end
+/// lines as well, so they arrive as `\n`.
+pub struct EventWriter<W: Write> {
+    writer: W,
+    last_write: Instant,
+}
+
+impl<W: Write> EventWriter<W> {
+    pub fn new(writer: W) -> EventWriter<W> {
+        EventWriter {
+            writer,
+            last_write: Instant::now(),
+        }
+    }
+
+    /// Writes a single event. Fails with `InvalidInput` if the id or type contain line breaks,
+    /// or the id contains NUL, as the client would parse a different event.
+    pub fn write_event(&mut self, event: &Event) -> io::Result<()> {
+        let mut buf = Vec::new();
+        if let Some(ref id) = event.id {
+            check_field("id", id, true)?;
+            writeln!(buf, "id: {}", id)?;
+        }
+        if let Some(ref event_type) = event.event_type {
+            check_field("event", event_type, false)?;
+            writeln!(buf, "event: {}", event_type)?;
+        }
+        for line in data_lines(&event.data) {
+            writeln!(buf, "data: {}", line)?;
+        }
+        buf.push(b'\n');
+        self.write(&buf)
+    }
+
+    /// Tells the client how long to wait before reconnecting.
+    pub fn retry(&mut self, retry: Duration) -> io::Result<()> {
+        let millis = retry.as_secs() * 1000 + retry.subsec_nanos() as u64 / 1_000_000;
+        self.write(format!("retry: {}\n\n", millis).as_bytes())
+    }
+
+    /// Writes a comment, which clients ignore.
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for line in data_lines(text) {
+            writeln!(buf, ":{}", line)?;
+        }
+        buf.push(b'\n');
+        self.write(&buf)
+    }
+
+    /// Writes an empty comment, so that proxies and clients with a read timeout keep the idle
+    /// connection open, and a closed connection is noticed.
+    pub fn keep_alive(&mut self) -> io::Result<()> {
+        self.comment("")
+    }
+
+    /// Writes a keep-alive comment if nothing was written for `interval`.
+    pub fn keep_alive_if_idle(&mut self, interval: Duration) -> io::Result<()> {
+        if self.last_write.elapsed() >= interval {
+            self.keep_alive()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the events of `buffer` the client missed: those after `last_event_id`, or all of
+    /// them if the client didn't send one or it is no longer in the buffer. Returns the number of
+    /// events written.
+    pub fn replay(&mut self, buffer: &ReplayBuffer, last_event_id: Option<&str>) -> io::Result<usize> {
+        let events = match last_event_id {
+            Some(id) => buffer.since(id).unwrap_or_else(|| buffer.events.iter().collect()),
+            None => buffer.events.iter().collect(),
+        };
+        for event in &events {
+            self.write_event(event)?;
+        }
+        Ok(events.len())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)?;
+        self.writer.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+fn check_field(name: &str, value: &str, id: bool) -> io::Result<()> {
+    if value.contains(&['\r', '\n'][..]) || (id && value.contains('\0')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid {} field: {:?}", name, value)));
+    }
+    Ok(())
+}
+
+/// Splits on `\r\n`, `\r` and `\n`, keeping empty lines at the end.
+fn data_lines(data: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = data;
+    while let Some(pos) = rest.find(&['\r', '\n'][..]) {
+        lines.push(&rest[..pos]);
+        let len = if rest[pos..].starts_with("\r\n") { 2 } else { 1 };
+        rest = &rest[pos + len..];
+    }
+    lines.push(rest);
+    lines
+}
+
+/// The most recent events with an id, to replay them to clients that reconnect with a
+/// `Last-Event-ID`.
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    events: VecDeque<Event>,
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    /// Creates a buffer that keeps at most `capacity` events.
+    pub fn new(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Adds an event, dropping the oldest one if the buffer is full. Events without an id can't
+    /// be resumed from and are not kept.
+    pub fn push(&mut self, event: Event) {
+        if event.id.is_none() || self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// The events after the one with the given id, or `None` if it isn't in the buffer, so that
+    /// the client may have missed more events than can be replayed.
+    pub fn since(&self, id: &str) -> Option<Vec<&Event>> {
+        let pos = self.events.iter().rposition(|e| e.id.as_ref().map(|i| &i[..]) == Some(id))?;
+        Some(self.events.iter().skip(pos + 1).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+/// The request of a client, as read by `handshake`.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path and query of the request.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub last_event_id: Option<String>,
+}
+
+impl Request {
+    /// The value of the first header with the given name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+}
+
+/// Upper bound of the size of a request head.
+const MAX_REQUEST: usize = 16 * 1024;
+
+/// Reads the head of an HTTP/1.x request from a connection and answers with the header of an
+/// event stream. The connection is closed by the server to end the stream.
+///
+/// This is just enough HTTP for a relay or mock backend: request bodies and keep-alive are not
+/// supported. Requests that can't be parsed are answered with `400 Bad Request` and fail with
+/// `InvalidData`.
+pub fn handshake<S: Read + Write>(mut stream: S) -> io::Result<(Request, EventWriter<S>)> {
+    let head = read_head(&mut stream)?;
+    match parse_request(&head) {
+        Some(request) => {
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\
+                               Content-Type: text/event-stream\r\n\
+                               Cache-Control: no-cache\r\n\
+                               Connection: close\r\n\r\n")?;
+            stream.flush()?;
+            Ok((request, EventWriter::new(stream)))
+        }
+        None => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP request"))
+        }
+    }
+}
+
+/// Reads up to the empty line ending the request head. Reads byte by byte so that nothing after
+/// it is consumed.
+fn read_head<R: Read>(stream: &mut R) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during request"));
+        }
+        if head.len() == MAX_REQUEST {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too large"));
+        }
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "request is not UTF-8"))
+}
+
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    if !request_line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let pos = line.find(':')?;
+        headers.push((line[..pos].trim().to_string(), line[pos + 1..].trim().to_string()));
+    }
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        last_event_id: None,
+    };
+    request.last_event_id = request.header("Last-Event-ID").map(str::to_string);
+    Some(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event::{Parsed, Parser};
+    use std::io::BufRead;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn event(id: Option<&str>, data: &str) -> Event {
+        Event {
+            id: id.map(str::to_string),
+            event_type: None,
+            data: data.to_string(),
+        }
+    }
+
+    fn written<F: FnOnce(&mut EventWriter<Vec<u8>>) -> io::Result<()>>(f: F) -> String {
+        let mut writer = EventWriter::new(Vec::new());
+        f(&mut writer).unwrap();
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn write_event() {
+        let e = Event { id: Some("1".to_string()), event_type: Some("put".to_string()), data: "a\n\nb".to_string() };
+        assert_eq!(written(|w| w.write_event(&e)), "id: 1\nevent: put\ndata: a\ndata: \ndata: b\n\n");
+        assert_eq!(written(|w| w.write_event(&event(None, ""))), "data: \n\n");
+        assert_eq!(written(|w| w.write_event(&event(None, "a\r\nb\rc\n"))), "data: a\ndata: b\ndata: c\ndata: \n\n");
+        assert_eq!(written(|w| w.retry(Duration::from_millis(1500))), "retry: 1500\n\n");
+        assert_eq!(written(|w| w.keep_alive()), ":\n\n");
+        assert_eq!(written(|w| w.comment("a\nb")), ":a\n:b\n\n");
+
+        let mut writer = EventWriter::new(Vec::new());
+        assert!(writer.write_event(&event(Some("1\n"), "")).is_err());
+        assert!(writer.write_event(&event(Some("1\0"), "")).is_err());
+        assert!(writer.write_event(&Event { id: None, event_type: Some("a\rb".to_string()), data: String::new() }).is_err());
+        assert!(writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let events = vec![event(Some("1"), ""), event(Some("2"), "a\n\nb\n"), event(Some("3"), " x ")];
+        let stream = written(|w| events.iter().try_for_each(|e| w.write_event(e)));
+        let parsed = Parser::new().feed(stream.as_bytes());
+        assert_eq!(parsed, events.into_iter().map(Parsed::Event).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn replay_buffer() {
+        let mut buffer = ReplayBuffer::new(3);
+        buffer.push(event(None, "not kept"));
+        for i in 1..6 {
+            buffer.push(event(Some(&i.to_string()), ""));
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.since("5").unwrap(), Vec::<&Event>::new());
+        assert_eq!(buffer.since("3").unwrap(), vec![&event(Some("4"), ""), &event(Some("5"), "")]);
+        assert_eq!(buffer.since("2"), None);
+
+        assert_eq!(written(|w| w.replay(&buffer, Some("4")).map(|n| assert_eq!(n, 1))), "id: 5\ndata: \n\n");
+        assert_eq!(written(|w| w.replay(&buffer, Some("1")).map(|n| assert_eq!(n, 3))).matches("id:").count(), 3);
+        assert_eq!(written(|w| w.replay(&buffer, None).map(|n| assert_eq!(n, 3))).matches("id:").count(), 3);
+    }
+
+    #[test]
+    fn mock_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut replay = ReplayBuffer::new(10);
+            for i in 1..4 {
+                replay.push(event(Some(&i.to_string()), &format!("event {}", i)));
+            }
+            let (request, mut writer) = handshake(listener.accept().unwrap().0).unwrap();
+            writer.retry(Duration::from_secs(1)).unwrap();
+            writer.replay(&replay, request.last_event_id.as_ref().map(|id| &id[..])).unwrap();
+            writer.keep_alive().unwrap();
+            request
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /events?a=b HTTP/1.1\r\nHost: localhost\r\nlast-event-id: 1\r\n\r\n").unwrap();
+        let mut reader = io::BufReader::new(client);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 200 OK\r\n");
+        let mut headers = String::new();
+        while !headers.ends_with("\r\n\r\n") {
+            reader.read_line(&mut headers).unwrap();
+        }
+        assert!(headers.contains("Content-Type: text/event-stream\r\n"));
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+
+        let request = server.join().unwrap();
+        assert_eq!((&request.method[..], &request.path[..]), ("GET", "/events?a=b"));
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.last_event_id, Some("1".to_string()));
+        assert_eq!(Parser::new().feed(&body), vec![
+            Parsed::Retry(Duration::from_secs(1)),
+            Parsed::Event(event(Some("2"), "event 2")),
+            Parsed::Event(event(Some("3"), "event 3")),
+        ]);
+    }
+
+    #[test]
+    fn bad_request() {
+        let mut stream = io::Cursor::new(b"hello\r\n\r\n".to_vec());
+        assert_eq!(handshake(&mut stream).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
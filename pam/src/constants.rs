@@ -5,6 +5,7 @@ use libc::{c_int, c_uint};
 pub type PamFlag = c_uint;
 pub type PamItemType = c_int;
 pub type PamMessageStyle = c_int;
+pub type PamLogPriority = c_int;
 pub type AlwaysZero = c_int;
 
 // The Linux-PAM flags
@@ -56,6 +57,19 @@ pub const PAM_TEXT_INFO: PamMessageStyle = 4;
 pub const PAM_RADIO_TYPE: PamMessageStyle = 5;
 pub const PAM_BINARY_PROMPT: PamMessageStyle = 7;
 
+// Syslog priorities for pam_syslog
+// see /usr/include/sys/syslog.h
+pub const LOG_EMERG: PamLogPriority = 0;
+pub const LOG_ALERT: PamLogPriority = 1;
+pub const LOG_CRIT: PamLogPriority = 2;
+pub const LOG_ERR: PamLogPriority = 3;
+pub const LOG_WARNING: PamLogPriority = 4;
+pub const LOG_NOTICE: PamLogPriority = 5;
+pub const LOG_INFO: PamLogPriority = 6;
+pub const LOG_DEBUG: PamLogPriority = 7;
+/// security/authorization messages (private)
+pub const LOG_AUTHPRIV: PamLogPriority = 10 << 3;
+
 // The Linux-PAM return values
 // see /usr/include/security/_pam_types.h
 #[allow(non_camel_case_types, dead_code)]
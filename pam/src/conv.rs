@@ -1,5 +1,5 @@
-use libc::{c_char, c_int};
-use std::ptr;
+use libc::{c_char, c_int, c_void, free};
+use std::{ptr, slice};
 use std::ffi::{CStr, CString};
 
 use constants::PamResultCode;
@@ -10,15 +10,107 @@ use module::{PamItem, PamResult};
 pub enum AppDataPtr {}
 
 #[repr(C)]
-struct PamMessage {
-    msg_style: PamMessageStyle,
-    msg: *const c_char,
+pub(crate) struct PamMessage {
+    pub(crate) msg_style: PamMessageStyle,
+    pub(crate) msg: *const c_char,
 }
 
 #[repr(C)]
-struct PamResponse {
-    resp: *const c_char,
-    resp_retcode: AlwaysZero,
+pub(crate) struct PamResponse {
+    pub(crate) resp: *mut c_char,
+    pub(crate) resp_retcode: AlwaysZero,
+}
+
+/// The signature of the conversation function provided by the application.
+pub(crate) type ConvFn = extern "C" fn(num_msg: c_int,
+                                       pam_message: *const *const PamMessage,
+                                       pam_response: *mut *mut PamResponse,
+                                       appdata_ptr: *const AppDataPtr)
+                                       -> PamResultCode;
+
+/// A message for `PamConv::converse`.
+pub enum Message<'a> {
+    PromptEchoOff(&'a str),
+    PromptEchoOn(&'a str),
+    ErrorMsg(&'a str),
+    TextInfo(&'a str),
+    /// A yes/no/maybe question.
+    RadioType(&'a str),
+    /// A Linux-PAM binary prompt with its control byte and data, for clients
+    /// that understand a binary protocol agreed on with the module.
+    BinaryPrompt(u8, &'a [u8]),
+}
+
+/// The answer of the user to a single `Message`.
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    /// The client didn't answer, as is usual for informational messages, or
+    /// answered with text that isn't valid UTF-8.
+    None,
+    Text(String),
+    /// The control byte and data of the answer to a binary prompt.
+    Binary(u8, Vec<u8>),
+}
+
+/// The size of the length and control fields of a binary prompt.
+const BINARY_HEADER: usize = 5;
+
+impl<'a> Message<'a> {
+    fn style(&self) -> PamMessageStyle {
+        match *self {
+            Message::PromptEchoOff(_) => PAM_PROMPT_ECHO_OFF,
+            Message::PromptEchoOn(_) => PAM_PROMPT_ECHO_ON,
+            Message::ErrorMsg(_) => PAM_ERROR_MSG,
+            Message::TextInfo(_) => PAM_TEXT_INFO,
+            Message::RadioType(_) => PAM_RADIO_TYPE,
+            Message::BinaryPrompt(_, _) => PAM_BINARY_PROMPT,
+        }
+    }
+
+    /// Encodes the message as expected by the conversation function: a C
+    /// string, or a big-endian length including the header, the control
+    /// byte and the data for binary prompts.
+    fn to_bytes(&self) -> PamResult<Vec<u8>> {
+        match *self {
+            Message::PromptEchoOff(s) | Message::PromptEchoOn(s) | Message::ErrorMsg(s) |
+            Message::TextInfo(s) | Message::RadioType(s) => {
+                CString::new(s).map(|c| c.into_bytes_with_nul()).map_err(|_| PamResultCode::PAM_BUF_ERR)
+            }
+            Message::BinaryPrompt(control, data) => {
+                let length = data.len() + BINARY_HEADER;
+                if length > u32::MAX as usize {
+                    return Err(PamResultCode::PAM_BUF_ERR);
+                }
+                let mut bytes = Vec::with_capacity(length);
+                bytes.extend_from_slice(&[(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8, control]);
+                bytes.extend_from_slice(data);
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// Reads a response and wipes it, as it may well contain a password.
+unsafe fn take_response(style: PamMessageStyle, resp: *mut c_char) -> PamResult<Response> {
+    if resp.is_null() {
+        return Ok(Response::None);
+    }
+
+    if style == PAM_BINARY_PROMPT {
+        let header = slice::from_raw_parts(resp as *const u8, BINARY_HEADER);
+        let length = (header[0] as usize) << 24 | (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize;
+        if length < BINARY_HEADER {
+            return Err(PamResultCode::PAM_CONV_ERR);
+        }
+        let response = Response::Binary(header[4], slice::from_raw_parts(resp.add(BINARY_HEADER) as *const u8, length - BINARY_HEADER).to_vec());
+        ptr::write_bytes(resp, 0, length);
+        Ok(response)
+    } else {
+        let bytes = CStr::from_ptr(resp).to_bytes();
+        let response = String::from_utf8(bytes.to_vec()).map(Response::Text).unwrap_or(Response::None);
+        ptr::write_bytes(resp, 0, bytes.len());
+        Ok(response)
+    }
 }
 
 /// `PamConv` acts as a channel for communicating with user.
@@ -28,15 +120,19 @@ struct PamResponse {
 /// will be relayed back.
 #[repr(C)]
 pub struct PamConv {
-    conv: extern "C" fn(num_msg: c_int,
-                        pam_message: &&PamMessage,
-                        pam_response: &mut *const PamResponse,
-                        appdata_ptr: *const AppDataPtr)
-                        -> PamResultCode,
+    conv: ConvFn,
     appdata_ptr: *const AppDataPtr,
 }
 
 impl PamConv {
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn new(conv: ConvFn, appdata_ptr: *const AppDataPtr) -> PamConv {
+        PamConv {
+            conv,
+            appdata_ptr,
+        }
+    }
+
     /// Sends a message to the pam client.
     ///
     /// This will typically result in the user seeing a message or a prompt.
@@ -47,31 +143,65 @@ impl PamConv {
     /// - PAM_ERROR_MSG
     /// - PAM_TEXT_INFO
     /// - PAM_RADIO_TYPE
-    /// - PAM_BINARY_PROMPT
+    ///
+    /// Use `converse` for PAM_BINARY_PROMPT.
     ///
     /// Note that the user experience will depend on how the client implements
     /// these message styles - and not all applications implement all message
     /// styles.
     pub fn send(&self, style: PamMessageStyle, msg: &str) -> PamResult<Option<String>> {
-        let mut resp_ptr: *const PamResponse = ptr::null();
-        let message = CString::new(msg).unwrap();
-        let msg = PamMessage {
-            msg_style: style,
-            msg: message.as_ptr(),
+        let message = match style {
+            PAM_PROMPT_ECHO_OFF => Message::PromptEchoOff(msg),
+            PAM_PROMPT_ECHO_ON => Message::PromptEchoOn(msg),
+            PAM_ERROR_MSG => Message::ErrorMsg(msg),
+            PAM_TEXT_INFO => Message::TextInfo(msg),
+            PAM_RADIO_TYPE => Message::RadioType(msg),
+            _ => return Err(PamResultCode::PAM_CONV_ERR),
         };
 
-        let ret = (self.conv)(1, &&msg, &mut resp_ptr, self.appdata_ptr);
+        match self.converse(&[message])?.pop() {
+            Some(Response::Text(s)) => Ok(Some(s)),
+            _ => Ok(None),
+        }
+    }
 
-        if PamResultCode::PAM_SUCCESS == ret {
-            if resp_ptr.is_null() || unsafe {(*resp_ptr).resp.is_null()} {
-                Ok(None)
-            } else {
-                let bytes = unsafe {CStr::from_ptr((*resp_ptr).resp).to_bytes()};
-                Ok(String::from_utf8(bytes.to_vec()).ok())
-            }
-        } else {
-            Err(ret)
+    /// Sends several messages to the pam client in a single round trip, and
+    /// returns one response per message.
+    ///
+    /// This allows, for example, showing a banner and asking for a code with
+    /// one prompt.  The responses are wiped and freed before returning.
+    pub fn converse(&self, messages: &[Message]) -> PamResult<Vec<Response>> {
+        if messages.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let bytes = messages.iter().map(|m| m.to_bytes()).collect::<PamResult<Vec<Vec<u8>>>>()?;
+        let pam_messages: Vec<PamMessage> = messages.iter().zip(bytes.iter()).map(|(m, b)| PamMessage {
+            msg_style: m.style(),
+            msg: b.as_ptr() as *const c_char,
+        }).collect();
+        let pointers: Vec<*const PamMessage> = pam_messages.iter().map(|m| m as *const PamMessage).collect();
+
+        let mut resp_ptr: *mut PamResponse = ptr::null_mut();
+        let ret = (self.conv)(pointers.len() as c_int, pointers.as_ptr(), &mut resp_ptr, self.appdata_ptr);
+        if PamResultCode::PAM_SUCCESS != ret {
+            return Err(ret);
+        }
+        if resp_ptr.is_null() {
+            return Ok(messages.iter().map(|_| Response::None).collect());
         }
+
+        // Take ownership of every response before checking any of them, so
+        // that all of them are wiped and freed.
+        let responses: Vec<PamResult<Response>> = messages.iter().enumerate().map(|(i, m)| unsafe {
+            let resp = (*resp_ptr.add(i)).resp;
+            let response = take_response(m.style(), resp);
+            free(resp as *mut c_void);
+            response
+        }).collect();
+        unsafe { free(resp_ptr as *mut c_void) };
+
+        responses.into_iter().collect()
     }
 }
 
@@ -1,6 +1,6 @@
 use constants::{PamItemType, PAM_SERVICE, PAM_USER, PAM_USER_PROMPT, PAM_TTY, PAM_RUSER, PAM_RHOST,
-                PAM_AUTHTOK, PAM_OLDAUTHTOK};
-use module::PamItem;
+                PAM_AUTHTOK, PAM_OLDAUTHTOK, PAM_FAIL_DELAY, PAM_XDISPLAY, PAM_AUTHTOK_TYPE};
+use module::{PamItem, PamStringItem};
 pub use conv::PamConv;
 
 
@@ -12,6 +12,8 @@ impl PamItem for PamService {
     }
 }
 
+impl PamStringItem for PamService {}
+
 pub struct PamUser {}
 
 impl PamItem for PamUser {
@@ -20,6 +22,8 @@ impl PamItem for PamUser {
     }
 }
 
+impl PamStringItem for PamUser {}
+
 pub struct PamUserPrompt {}
 
 impl PamItem for PamUserPrompt {
@@ -28,6 +32,8 @@ impl PamItem for PamUserPrompt {
     }
 }
 
+impl PamStringItem for PamUserPrompt {}
+
 pub struct PamTty {}
 
 impl PamItem for PamTty {
@@ -36,6 +42,8 @@ impl PamItem for PamTty {
     }
 }
 
+impl PamStringItem for PamTty {}
+
 pub struct PamRUser {}
 
 impl PamItem for PamRUser {
@@ -44,6 +52,8 @@ impl PamItem for PamRUser {
     }
 }
 
+impl PamStringItem for PamRUser {}
+
 pub struct PamRHost {}
 
 impl PamItem for PamRHost {
@@ -52,6 +62,8 @@ impl PamItem for PamRHost {
     }
 }
 
+impl PamStringItem for PamRHost {}
+
 pub struct PamAuthTok {}
 
 impl PamItem for PamAuthTok {
@@ -60,6 +72,8 @@ impl PamItem for PamAuthTok {
     }
 }
 
+impl PamStringItem for PamAuthTok {}
+
 pub struct PamOldAuthTok {}
 
 impl PamItem for PamOldAuthTok {
@@ -67,3 +81,35 @@ impl PamItem for PamOldAuthTok {
         PAM_OLDAUTHTOK
     }
 }
+
+impl PamStringItem for PamOldAuthTok {}
+
+/// The function an application provides to override the delay after failed
+/// authentication, see `PamHandle::set_fail_delay_fn`.
+pub struct PamFailDelay {}
+
+impl PamItem for PamFailDelay {
+    fn item_type() -> PamItemType {
+        PAM_FAIL_DELAY
+    }
+}
+
+pub struct PamXDisplay {}
+
+impl PamItem for PamXDisplay {
+    fn item_type() -> PamItemType {
+        PAM_XDISPLAY
+    }
+}
+
+impl PamStringItem for PamXDisplay {}
+
+pub struct PamAuthTokType {}
+
+impl PamItem for PamAuthTokType {
+    fn item_type() -> PamItemType {
+        PAM_AUTHTOK_TYPE
+    }
+}
+
+impl PamStringItem for PamAuthTokType {}
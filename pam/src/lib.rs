@@ -23,6 +23,9 @@
 //! hard-coded in the `constants` module.  The values there are taken from
 //! a Linux system.  That means that it might take some work to get this library
 //! to work on other platforms.
+//!
+//! With the `testing` feature, libpam is replaced by the fake pam stack in the
+//! `testing` module, so that hooks can be run in ordinary unit tests.
 
 extern crate libc;
 
@@ -32,3 +35,5 @@ pub mod conv;
 pub mod constants;
 pub mod items;
 pub mod module;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
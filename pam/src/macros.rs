@@ -1,3 +1,10 @@
+use std::{
+	any::Any,
+	cell::RefCell,
+	panic::{self, UnwindSafe},
+	sync::Once,
+};
+
 /// Macro to generate the `extern "C"` entrypoint bindings needed by PAM
 ///
 /// You can call `pam_hooks!(SomeType);` for any type that implements `PamHooks`
@@ -35,9 +42,10 @@ macro_rules! pam_hooks {
 		pub use self::pam_hooks_scope::*;
 		mod pam_hooks_scope {
 			use $crate::module::{PamHandle, PamHooks};
-			use $crate::constants::{PamFlag, PamResultCode};
+			use $crate::constants::{PamFlag, PamResultCode, LOG_CRIT};
 			use std::ffi::CStr;
 			use std::os::raw::{c_char, c_int};
+			use std::panic::AssertUnwindSafe;
 
 			fn extract_argv<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
 				(0..argc)
@@ -47,6 +55,25 @@ macro_rules! pam_hooks {
 					.collect()
 			}
 
+			/// Runs a hook, making sure a panic never unwinds into the application.
+			fn call_hook(
+				name: &str,
+				hook: fn(&PamHandle, Vec<&CStr>, PamFlag) -> PamResultCode,
+				pamh: &PamHandle,
+				flags: PamFlag,
+				argc: c_int,
+				argv: *const *const c_char,
+			) -> PamResultCode {
+				let msg = match $crate::macros::catch_panic(AssertUnwindSafe(|| hook(pamh, extract_argv(argc, argv), flags))) {
+					Ok(res) => return res,
+					Err(msg) => msg,
+				};
+				pamh.syslog(LOG_CRIT, &format!("{} panicked: {}", name, msg));
+
+				$crate::macros::catch_panic(AssertUnwindSafe(|| super::$ident::panic_result(pamh, extract_argv(argc, argv), flags)))
+					.unwrap_or(PamResultCode::PAM_SYSTEM_ERR)
+			}
+
 			#[no_mangle]
 			pub extern "C" fn pam_sm_acct_mgmt(
 				pamh: &PamHandle,
@@ -54,8 +81,7 @@ macro_rules! pam_hooks {
 				argc: c_int,
 				argv: *const *const c_char,
 			) -> PamResultCode {
-				let args = extract_argv(argc, argv);
-				super::$ident::acct_mgmt(pamh, args, flags)
+				call_hook("pam_sm_acct_mgmt", super::$ident::acct_mgmt, pamh, flags, argc, argv)
 			}
 
 			#[no_mangle]
@@ -65,8 +91,7 @@ macro_rules! pam_hooks {
 				argc: c_int,
 				argv: *const *const c_char,
 			) -> PamResultCode {
-				let args = extract_argv(argc, argv);
-				super::$ident::sm_authenticate(pamh, args, flags)
+				call_hook("pam_sm_authenticate", super::$ident::sm_authenticate, pamh, flags, argc, argv)
 			}
 
 			#[no_mangle]
@@ -76,8 +101,7 @@ macro_rules! pam_hooks {
 				argc: c_int,
 				argv: *const *const c_char,
 			) -> PamResultCode {
-				let args = extract_argv(argc, argv);
-				super::$ident::sm_chauthtok(pamh, args, flags)
+				call_hook("pam_sm_chauthtok", super::$ident::sm_chauthtok, pamh, flags, argc, argv)
 			}
 
 			#[no_mangle]
@@ -87,8 +111,7 @@ macro_rules! pam_hooks {
 				argc: c_int,
 				argv: *const *const c_char,
 			) -> PamResultCode {
-				let args = extract_argv(argc, argv);
-				super::$ident::sm_close_session(pamh, args, flags)
+				call_hook("pam_sm_close_session", super::$ident::sm_close_session, pamh, flags, argc, argv)
 			}
 
 			#[no_mangle]
@@ -98,8 +121,7 @@ macro_rules! pam_hooks {
 				argc: c_int,
 				argv: *const *const c_char,
 			) -> PamResultCode {
-				let args = extract_argv(argc, argv);
-				super::$ident::sm_open_session(pamh, args, flags)
+				call_hook("pam_sm_open_session", super::$ident::sm_open_session, pamh, flags, argc, argv)
 			}
 
 			#[no_mangle]
@@ -109,19 +131,78 @@ macro_rules! pam_hooks {
 				argc: c_int,
 				argv: *const *const c_char,
 			) -> PamResultCode {
-				let args = extract_argv(argc, argv);
-				super::$ident::sm_setcred(pamh, args, flags)
+				call_hook("pam_sm_setcred", super::$ident::sm_setcred, pamh, flags, argc, argv)
 			}
 		}
 	)
 }
 
+thread_local! {
+	/// Where the panic being caught on this thread happened, if `catch_panic` runs.
+	static CAUGHT: RefCell<Option<Option<String>>> = const { RefCell::new(None) };
+}
+static INSTALL_HOOK: Once = Once::new();
+
+/// Runs `f`, returning the message and location of a panic instead of unwinding. Unlike the
+/// default panic hook, nothing is written to stderr, which belongs to the application.
+pub fn catch_panic<F: FnOnce() -> R + UnwindSafe, R>(f: F) -> Result<R, String> {
+	INSTALL_HOOK.call_once(|| {
+		// Panics outside of `catch_panic`, such as those of other threads, are left to the
+		// previous hook.
+		let previous = panic::take_hook();
+		panic::set_hook(Box::new(move |info| {
+			let caught = CAUGHT.with(|c| match *c.borrow_mut() {
+				Some(ref mut caught) => {
+					let location = info.location().map_or(String::from("unknown location"), |l| format!("{}:{}", l.file(), l.line()));
+					*caught = Some(format!("{} at {}", payload_message(info.payload()), location));
+					true
+				}
+				None => false,
+			});
+			if !caught {
+				previous(info);
+			}
+		}));
+	});
+
+	let outer = CAUGHT.with(|c| c.replace(Some(None)));
+	let result = panic::catch_unwind(f);
+	let caught = CAUGHT.with(|c| c.replace(outer));
+	result.map_err(|payload| caught.and_then(|c| c).unwrap_or_else(|| String::from(payload_message(&*payload))))
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+	match payload.downcast_ref::<&str>() {
+		Some(s) => s,
+		None => match payload.downcast_ref::<String>() {
+			Some(s) => &s[..],
+			None => "Box<Any>",
+		},
+	}
+}
+
 #[cfg(test)]
 pub mod test {
+	use super::catch_panic;
 	use module::PamHooks;
 
 	struct Foo;
 	impl PamHooks for Foo {}
 
 	pam_hooks!(Foo);
+
+	#[test]
+	fn catches_panics_with_location() {
+		assert_eq!(catch_panic(|| 1), Ok(1));
+		let msg = catch_panic(|| -> () { panic!("boom {}", 1) }).unwrap_err();
+		assert!(msg.starts_with("boom 1 at "), "{}", msg);
+		assert!(msg.contains("macros.rs:"), "{}", msg);
+
+		// Nested calls keep their own panics.
+		let outer = catch_panic(|| {
+			assert!(catch_panic(|| -> () { panic!("inner") }).unwrap_err().starts_with("inner at "));
+			panic!("outer")
+		}).unwrap_err();
+		assert!(outer.starts_with("outer at "), "{}", outer);
+	}
 }
\ No newline at end of file
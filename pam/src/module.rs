@@ -1,10 +1,11 @@
 //! Functions for use in pam modules.
 
-use libc::c_char;
+use libc::{c_char, c_int, c_uint, c_void};
 use std::{mem, ptr};
 use std::ffi::{CStr, CString};
 
-use constants::{PamResultCode, PamItemType, PamFlag};
+use constants::{PamResultCode, PamItemType, PamFlag, PamLogPriority, LOG_AUTHPRIV};
+use items::PamFailDelay;
 
 /// Opaque type, used as a pointer when making pam API calls.
 ///
@@ -12,14 +13,23 @@ use constants::{PamResultCode, PamItemType, PamFlag};
 /// Such a call provides a pam handle pointer.  The same pointer should be given
 /// as an argument when making API calls.
 #[allow(missing_copy_implementations)]
-pub enum PamHandle {}
+#[repr(C)]
+pub struct PamHandle {
+    _private: [u8; 0],
+}
 
 #[allow(missing_copy_implementations)]
-enum PamItemT {}
+pub(crate) enum PamItemT {}
 
 #[allow(missing_copy_implementations)]
 pub enum PamDataT {}
 
+// With the `testing` feature, the same functions are provided by the fake pam
+// stack instead of libpam.
+#[cfg(any(test, feature = "testing"))]
+use testing::ffi::*;
+
+#[cfg(not(any(test, feature = "testing")))]
 #[link(name = "pam")]
 extern "C" {
     fn pam_get_data(pamh: *const PamHandle,
@@ -42,13 +52,30 @@ extern "C" {
 
     fn pam_set_item(pamh: *mut PamHandle,
                     item_type: PamItemType,
-                    item: &PamItemT)
+                    item: *const PamItemT)
                     -> PamResultCode;
 
+    fn pam_fail_delay(pamh: *const PamHandle,
+                      usec: c_uint)
+                      -> PamResultCode;
+
     fn pam_get_user(pamh: *const PamHandle,
-                    user: &*mut c_char,
+                    user: *mut *const c_char,
                     prompt: *const c_char)
                     -> PamResultCode;
+
+    fn pam_putenv(pamh: *const PamHandle,
+                  name_value: *const c_char)
+                  -> PamResultCode;
+
+    fn pam_getenv(pamh: *const PamHandle,
+                  name: *const c_char)
+                  -> *const c_char;
+
+    fn pam_syslog(pamh: *const PamHandle,
+                  priority: PamLogPriority,
+                  fmt: *const c_char,
+                  ...);
 }
 
 #[no_mangle]
@@ -75,6 +102,17 @@ pub trait PamItem {
     fn item_type() -> PamItemType;
 }
 
+/// Marker for items whose value is a string, which can be read with
+/// `get_item_str` and written with `set_item_str`.
+///
+/// All items are strings, except `PAM_CONV`, `PAM_FAIL_DELAY` and
+/// `PAM_XAUTHDATA`.
+pub trait PamStringItem: PamItem {}
+
+/// The function an application can set as `PAM_FAIL_DELAY` to handle the
+/// delay after a failed authentication itself.
+pub type PamFailDelayFn = extern "C" fn(retval: c_int, usec_delay: c_uint, appdata_ptr: *mut c_void);
+
 
 impl PamHandle {
     /// Gets some value, identified by `key`, that has been set by the module
@@ -135,23 +173,60 @@ impl PamHandle {
         }
     }
 
-    /// Sets a value in the pam context. The value can be retrieved using
-    /// `get_item`.
+    /// Retrieves the value of a string item, such as `PamAuthTok` or `PamRHost`.
     ///
-    /// Note that all items are strings, except `PAM_CONV` and `PAM_FAIL_DELAY`.
+    /// Returns `None` if the item has not been set, and an error if it can't be read or isn't
+    /// UTF-8, which callers should not mistake for an unset item.
+    ///
+    /// See `pam_get_item` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    pub fn get_item_str<T: PamStringItem>(&self) -> PamResult<Option<String>> {
+        let mut ptr: *const PamItemT = ptr::null();
+        let res = unsafe { pam_get_item(self, T::item_type(), &mut ptr) };
+        if PamResultCode::PAM_SUCCESS != res {
+            return Err(res);
+        }
+        if ptr.is_null() {
+            return Ok(None);
+        }
+        let bytes = unsafe { CStr::from_ptr(ptr as *const c_char).to_bytes() };
+        String::from_utf8(bytes.to_vec()).map(Some).map_err(|_| PamResultCode::PAM_CONV_ERR)
+    }
+
+    /// Sets a string item in the pam context. The value can be retrieved using
+    /// `get_item_str`.
     ///
     /// See `pam_set_item` in
     /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
-    pub fn set_item_str<T: PamItem>(&mut self, item: &str) -> PamResult<()> {
-        let c_item = CString::new(item).unwrap();
+    pub fn set_item_str<T: PamStringItem>(&self, item: &str) -> PamResult<()> {
+        let c_item = CString::new(item).map_err(|_| PamResultCode::PAM_BUF_ERR)?;
 
+        // pam_set_item copies the string, so c_item may be dropped afterwards.
         let res = unsafe {
-            pam_set_item(self,
+            pam_set_item(self as *const PamHandle as *mut PamHandle,
                         T::item_type(),
+                        c_item.as_ptr() as *const PamItemT)
+        };
+        if PamResultCode::PAM_SUCCESS == res {
+            Ok(())
+        } else {
+            Err(res)
+        }
+    }
 
-                        // unwrapping is okay here, as c_item will not be a NULL
-                        // pointer
-                        (c_item.as_ptr() as *const PamItemT).as_ref().unwrap())
+    /// Sets the `PAM_FAIL_DELAY` item, the function that handles the delay
+    /// after a failed authentication instead of libpam.  `None` restores the
+    /// default behavior.
+    ///
+    /// See `pam_set_item` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    pub fn set_fail_delay_fn(&self, delay_fn: Option<PamFailDelayFn>) -> PamResult<()> {
+        let ptr = match delay_fn {
+            Some(f) => f as *const PamItemT,
+            None => ptr::null(),
+        };
+        let res = unsafe {
+            pam_set_item(self as *const PamHandle as *mut PamHandle, PamFailDelay::item_type(), ptr)
         };
         if PamResultCode::PAM_SUCCESS == res {
             Ok(())
@@ -160,6 +235,21 @@ impl PamHandle {
         }
     }
 
+    /// Requests a delay of at least `usec` microseconds after a failed
+    /// authentication, to slow down guessing.  libpam uses the longest delay
+    /// requested by any module of the stack.
+    ///
+    /// See `pam_fail_delay` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-see-programming-libs.html
+    pub fn fail_delay(&self, usec: u32) -> PamResult<()> {
+        let res = unsafe { pam_fail_delay(self, usec as c_uint) };
+        if PamResultCode::PAM_SUCCESS == res {
+            Ok(())
+        } else {
+            Err(res)
+        }
+    }
+
     /// Retrieves the name of the user who is authenticating or logging in.
     ///
     /// This is really a specialization of `get_item`.
@@ -167,21 +257,71 @@ impl PamHandle {
     /// See `pam_get_user` in
     /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
     pub fn get_user(&self, prompt: Option<&str>) -> PamResult<String> {
-        let ptr: *mut c_char = ptr::null_mut();
+        let mut ptr: *const c_char = ptr::null();
         let res = if let Some(p) = prompt {
             let c_prompt = CString::new(p).unwrap();
-            unsafe { pam_get_user(self, &ptr, c_prompt.as_ptr()) }
+            unsafe { pam_get_user(self, &mut ptr, c_prompt.as_ptr()) }
         } else {
-            unsafe { pam_get_user(self, &ptr, ptr::null()) }
+            unsafe { pam_get_user(self, &mut ptr, ptr::null()) }
         };
         if PamResultCode::PAM_SUCCESS == res && !ptr.is_null() {
-            let const_ptr = ptr as *const c_char;
-            let bytes = unsafe { CStr::from_ptr(const_ptr).to_bytes() };
+            let bytes = unsafe { CStr::from_ptr(ptr).to_bytes() };
             String::from_utf8(bytes.to_vec()).map_err(|_| PamResultCode::PAM_CONV_ERR)
         } else {
             Err(res)
         }
     }
+
+    /// Sets, changes or deletes a variable in the pam environment, which the
+    /// application exports to the session of the user.
+    ///
+    /// `name_value` has the form `NAME=value` to set a variable and `NAME` to
+    /// delete it.
+    ///
+    /// See `pam_putenv` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    pub fn putenv(&self, name_value: &str) -> PamResult<()> {
+        let c_name_value = CString::new(name_value).map_err(|_| PamResultCode::PAM_BUF_ERR)?;
+        let res = unsafe { pam_putenv(self, c_name_value.as_ptr()) };
+        if PamResultCode::PAM_SUCCESS == res {
+            Ok(())
+        } else {
+            Err(res)
+        }
+    }
+
+    /// Logs a message to the `LOG_AUTHPRIV` syslog facility, prefixed with the
+    /// name of the service and the module like other pam modules do.
+    ///
+    /// See `pam_syslog` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-see-programming-libs.html
+    pub fn syslog(&self, priority: PamLogPriority, msg: &str) {
+        let c_msg = match CString::new(msg) {
+            Ok(m) => m,
+            Err(_) => CString::new(msg.replace('\0', "\\0")).unwrap_or_default(),
+        };
+        unsafe {
+            pam_syslog(self, LOG_AUTHPRIV | priority, "%s\0".as_ptr() as *const c_char, c_msg.as_ptr());
+        }
+    }
+
+    /// Retrieves a variable from the pam environment, or `None` if it is not set.
+    ///
+    /// See `pam_getenv` in
+    /// http://www.linux-pam.org/Linux-PAM-html/mwg-expected-by-module-item.html
+    pub fn getenv(&self, name: &str) -> Option<String> {
+        let c_name = match CString::new(name) {
+            Ok(n) => n,
+            Err(_) => return None,
+        };
+        let ptr = unsafe { pam_getenv(self, c_name.as_ptr()) };
+        if ptr.is_null() {
+            None
+        } else {
+            let bytes = unsafe { CStr::from_ptr(ptr).to_bytes() };
+            String::from_utf8(bytes.to_vec()).ok()
+        }
+    }
 }
 
 /// Provides functions that are invoked by the entrypoints generated by the
@@ -233,4 +373,11 @@ pub trait PamHooks {
 	fn sm_setcred(pamh: &PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
 		PamResultCode::PAM_IGNORE
 	}
+
+    /// This function determines the result of a hook that panicked. The panic is caught at the
+    /// boundary to the application, as unwinding into C is undefined behavior, and logged to
+    /// syslog before this function is called with the arguments of the hook.
+	fn panic_result(pamh: &PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+		PamResultCode::PAM_SYSTEM_ERR
+	}
 }
\ No newline at end of file
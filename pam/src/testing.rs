@@ -0,0 +1,459 @@
+//! An in-process fake of the pam stack, for unit-testing `PamHooks`
+//! implementations without libpam.
+//!
+//! With the `testing` feature, the pam API functions used by `PamHandle` are
+//! served by the `FakePam` behind the handle instead of libpam.  A `FakePam`
+//! has configurable items, a scripted conversation which records the messages
+//! it was sent, and keeps the values stored with `set_data`, the pam
+//! environment and the messages logged with `syslog`.
+//!
+//! ```ignore
+//! let pam = FakePam::new()
+//!     .with_item::<PamUser>("alice")
+//!     .with_response(Response::Text(String::from("secret")));
+//! assert_eq!(MyModule::sm_authenticate(pam.handle(), vec![], 0), PamResultCode::PAM_SUCCESS);
+//! assert_eq!(pam.item::<PamAuthTok>(), Some(String::from("secret")));
+//! ```
+
+use libc::{c_char, c_int, c_void, calloc, free, malloc};
+use std::{ptr, slice};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::sync::Mutex;
+
+use constants::*;
+use conv::{AppDataPtr, PamConv, PamMessage, PamResponse, Response};
+use items::PamUserPrompt;
+use module::{PamDataT, PamHandle, PamItem, PamStringItem};
+
+type Cleanup = extern "C" fn(pamh: *const PamHandle, data: Box<PamDataT>, error_status: PamResultCode);
+
+/// A message as received by the conversation function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SentMessage {
+    PromptEchoOff(String),
+    PromptEchoOn(String),
+    ErrorMsg(String),
+    TextInfo(String),
+    RadioType(String),
+    BinaryPrompt(u8, Vec<u8>),
+}
+
+/// The scripted client side of the conversation.
+struct Conversation {
+    responses: VecDeque<Response>,
+    sent: Vec<SentMessage>,
+}
+
+/// A fake pam handle.
+///
+/// The fake must stay in place while the handle returned by `handle` is in
+/// use, as the handle points to it.  Its state is behind mutexes, as the
+/// handle may be used from several threads like a real one.
+pub struct FakePam {
+    items: Mutex<HashMap<PamItemType, CString>>,
+    fail_delay_fn: Mutex<*const c_void>,
+    data: Mutex<HashMap<CString, (*mut PamDataT, Cleanup)>>,
+    env: Mutex<HashMap<String, CString>>,
+    logs: Mutex<Vec<(PamLogPriority, String)>>,
+    fail_delay: Mutex<Option<u32>>,
+    conversation: Box<Mutex<Conversation>>,
+    conv: Box<PamConv>,
+}
+
+impl FakePam {
+    pub fn new() -> FakePam {
+        let conversation = Box::new(Mutex::new(Conversation {
+            responses: VecDeque::new(),
+            sent: Vec::new(),
+        }));
+        let appdata_ptr = &*conversation as *const Mutex<Conversation> as *const AppDataPtr;
+        FakePam {
+            items: Mutex::new(HashMap::new()),
+            fail_delay_fn: Mutex::new(ptr::null()),
+            data: Mutex::new(HashMap::new()),
+            env: Mutex::new(HashMap::new()),
+            logs: Mutex::new(Vec::new()),
+            fail_delay: Mutex::new(None),
+            conversation,
+            conv: Box::new(PamConv::new(converse, appdata_ptr)),
+        }
+    }
+
+    /// Sets a string item, as the application would with `pam_set_item`.
+    pub fn with_item<T: PamStringItem>(self, value: &str) -> FakePam {
+        self.items.lock().unwrap().insert(T::item_type(), CString::new(value).unwrap());
+        self
+    }
+
+    /// Sets a variable of the pam environment.
+    pub fn with_env(self, name: &str, value: &str) -> FakePam {
+        self.env.lock().unwrap().insert(String::from(name), CString::new(value).unwrap());
+        self
+    }
+
+    /// Queues the answer to the next prompt.  Prompts are answered in order;
+    /// once the script is exhausted, the conversation fails with PAM_CONV_ERR.
+    /// Informational and error messages are not answered.
+    pub fn with_response(self, response: Response) -> FakePam {
+        self.conversation.lock().unwrap().responses.push_back(response);
+        self
+    }
+
+    /// The handle to pass to the hooks under test.
+    pub fn handle(&self) -> &PamHandle {
+        unsafe { &*(self as *const FakePam as *const PamHandle) }
+    }
+
+    /// The current value of a string item.
+    pub fn item<T: PamStringItem>(&self) -> Option<String> {
+        self.items.lock().unwrap().get(&T::item_type()).map(|v| v.to_string_lossy().into_owned())
+    }
+
+    /// The messages sent through the conversation so far.
+    pub fn messages(&self) -> Vec<SentMessage> {
+        self.conversation.lock().unwrap().sent.clone()
+    }
+
+    /// Whether a value has been stored with `set_data` under `key`.
+    pub fn has_data(&self, key: &str) -> bool {
+        CString::new(key).map(|k| self.data.lock().unwrap().contains_key(&k)).unwrap_or(false)
+    }
+
+    /// A variable of the pam environment.
+    pub fn env(&self, name: &str) -> Option<String> {
+        self.env.lock().unwrap().get(name).map(|v| v.to_string_lossy().into_owned())
+    }
+
+    /// The messages logged so far, with their priority without the facility.
+    pub fn logs(&self) -> Vec<(PamLogPriority, String)> {
+        self.logs.lock().unwrap().clone()
+    }
+
+    /// The longest delay requested with `fail_delay`, if any.
+    pub fn fail_delay(&self) -> Option<u32> {
+        *self.fail_delay.lock().unwrap()
+    }
+}
+
+impl Default for FakePam {
+    fn default() -> FakePam {
+        FakePam::new()
+    }
+}
+
+impl Drop for FakePam {
+    /// Runs the cleanup functions of stored data, like `pam_end` does.
+    fn drop(&mut self) {
+        let data: Vec<_> = self.data.lock().unwrap().drain().collect();
+        for (_, (value, cleanup)) in data {
+            unsafe { cleanup(self.handle(), Box::from_raw(value), PamResultCode::PAM_SUCCESS) };
+        }
+    }
+}
+
+unsafe fn to_string(ptr: *const c_char) -> String {
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+/// Copies `bytes` into memory allocated with malloc, as the caller of the
+/// conversation function frees the responses.
+unsafe fn malloc_copy(bytes: &[u8]) -> *mut c_char {
+    let copy = malloc(bytes.len() as _) as *mut u8;
+    if !copy.is_null() {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), copy, bytes.len());
+    }
+    copy as *mut c_char
+}
+
+extern "C" fn converse(num_msg: c_int,
+                       pam_message: *const *const PamMessage,
+                       pam_response: *mut *mut PamResponse,
+                       appdata_ptr: *const AppDataPtr)
+                       -> PamResultCode {
+    let conversation = unsafe { &*(appdata_ptr as *const Mutex<Conversation>) };
+    let mut conversation = conversation.lock().unwrap();
+    let responses = unsafe { calloc(num_msg as _, ::std::mem::size_of::<PamResponse>() as _) as *mut PamResponse };
+    if responses.is_null() {
+        return PamResultCode::PAM_BUF_ERR;
+    }
+
+    for i in 0..num_msg as isize {
+        let message = unsafe { &**pam_message.offset(i) };
+        let sent = unsafe {
+            match message.msg_style {
+                PAM_PROMPT_ECHO_OFF => SentMessage::PromptEchoOff(to_string(message.msg)),
+                PAM_PROMPT_ECHO_ON => SentMessage::PromptEchoOn(to_string(message.msg)),
+                PAM_ERROR_MSG => SentMessage::ErrorMsg(to_string(message.msg)),
+                PAM_TEXT_INFO => SentMessage::TextInfo(to_string(message.msg)),
+                PAM_RADIO_TYPE => SentMessage::RadioType(to_string(message.msg)),
+                PAM_BINARY_PROMPT => {
+                    let header = slice::from_raw_parts(message.msg as *const u8, 5);
+                    let length = (header[0] as usize) << 24 | (header[1] as usize) << 16 | (header[2] as usize) << 8 | header[3] as usize;
+                    SentMessage::BinaryPrompt(header[4], slice::from_raw_parts(message.msg.offset(5) as *const u8, length - 5).to_vec())
+                }
+                _ => {
+                    free_responses(responses, i);
+                    return PamResultCode::PAM_CONV_ERR;
+                }
+            }
+        };
+        let informational = matches!(sent, SentMessage::ErrorMsg(_) | SentMessage::TextInfo(_));
+        conversation.sent.push(sent);
+        if informational {
+            continue;
+        }
+
+        let resp = match conversation.responses.pop_front() {
+            Some(Response::None) => ptr::null_mut(),
+            Some(Response::Text(s)) => unsafe { malloc_copy(CString::new(s).unwrap().as_bytes_with_nul()) },
+            Some(Response::Binary(control, data)) => {
+                let length = data.len() + 5;
+                let mut bytes = vec![(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8, control];
+                bytes.extend_from_slice(&data);
+                unsafe { malloc_copy(&bytes) }
+            }
+            None => {
+                unsafe { free_responses(responses, i) };
+                return PamResultCode::PAM_CONV_ERR;
+            }
+        };
+        unsafe { (*responses.offset(i)).resp = resp };
+    }
+
+    unsafe { *pam_response = responses };
+    PamResultCode::PAM_SUCCESS
+}
+
+unsafe fn free_responses(responses: *mut PamResponse, count: isize) {
+    for i in 0..count {
+        free((*responses.offset(i)).resp as *mut c_void);
+    }
+    free(responses as *mut c_void);
+}
+
+/// Replacements for the libpam functions, with the same signatures.
+pub(crate) mod ffi {
+    use libc::{c_char, c_uint, c_void};
+    use std::ptr;
+    use std::ffi::{CStr, CString};
+
+    use constants::*;
+    use items::PamConv;
+    use module::{PamDataT, PamHandle, PamItemT};
+    use super::{Cleanup, FakePam, PamUserPrompt, PamItem};
+
+    unsafe fn fake<'a>(pamh: *const PamHandle) -> &'a FakePam {
+        &*(pamh as *const FakePam)
+    }
+
+    pub unsafe fn pam_get_data(pamh: *const PamHandle,
+                               module_data_name: *const c_char,
+                               data: &mut *const PamDataT)
+                               -> PamResultCode {
+        match fake(pamh).data.lock().unwrap().get(CStr::from_ptr(module_data_name)) {
+            Some(&(value, _)) => {
+                *data = value;
+                PamResultCode::PAM_SUCCESS
+            }
+            None => PamResultCode::PAM_NO_MODULE_DATA,
+        }
+    }
+
+    pub unsafe fn pam_set_data(pamh: *const PamHandle,
+                               module_data_name: *const c_char,
+                               data: Box<PamDataT>,
+                               cleanup: Cleanup)
+                               -> PamResultCode {
+        let key = CStr::from_ptr(module_data_name).to_owned();
+        let old = fake(pamh).data.lock().unwrap().insert(key, (Box::into_raw(data), cleanup));
+        if let Some((value, cleanup)) = old {
+            cleanup(pamh, Box::from_raw(value), PamResultCode::PAM_SUCCESS);
+        }
+        PamResultCode::PAM_SUCCESS
+    }
+
+    pub unsafe fn pam_get_item(pamh: *const PamHandle,
+                               item_type: PamItemType,
+                               item: &mut *const PamItemT)
+                               -> PamResultCode {
+        let fake = fake(pamh);
+        *item = match item_type {
+            PAM_CONV => &*fake.conv as *const PamConv as *const PamItemT,
+            PAM_FAIL_DELAY => *fake.fail_delay_fn.lock().unwrap() as *const PamItemT,
+            PAM_XAUTHDATA => return PamResultCode::PAM_BAD_ITEM,
+            _ => fake.items.lock().unwrap().get(&item_type).map_or(ptr::null(), |v| v.as_ptr() as *const PamItemT),
+        };
+        PamResultCode::PAM_SUCCESS
+    }
+
+    pub unsafe fn pam_set_item(pamh: *mut PamHandle,
+                               item_type: PamItemType,
+                               item: *const PamItemT)
+                               -> PamResultCode {
+        let fake = fake(pamh);
+        match item_type {
+            PAM_CONV | PAM_XAUTHDATA => return PamResultCode::PAM_BAD_ITEM,
+            PAM_FAIL_DELAY => *fake.fail_delay_fn.lock().unwrap() = item as *const c_void,
+            _ if item.is_null() => {
+                fake.items.lock().unwrap().remove(&item_type);
+            }
+            _ => {
+                fake.items.lock().unwrap().insert(item_type, CStr::from_ptr(item as *const c_char).to_owned());
+            }
+        }
+        PamResultCode::PAM_SUCCESS
+    }
+
+    pub unsafe fn pam_fail_delay(pamh: *const PamHandle, usec: c_uint) -> PamResultCode {
+        let fake = fake(pamh);
+        let mut fail_delay = fake.fail_delay.lock().unwrap();
+        if fail_delay.is_none_or(|d| d < usec) {
+            *fail_delay = Some(usec);
+        }
+        PamResultCode::PAM_SUCCESS
+    }
+
+    /// Prompts through the conversation if PAM_USER isn't set, like libpam.
+    pub unsafe fn pam_get_user(pamh: *const PamHandle,
+                               user: *mut *const c_char,
+                               prompt: *const c_char)
+                               -> PamResultCode {
+        let fake = fake(pamh);
+        if !fake.items.lock().unwrap().contains_key(&PAM_USER) {
+            let prompt = if !prompt.is_null() {
+                CStr::from_ptr(prompt).to_string_lossy().into_owned()
+            } else {
+                fake.items.lock().unwrap().get(&PamUserPrompt::item_type())
+                    .map_or(String::from("login: "), |p| p.to_string_lossy().into_owned())
+            };
+            match fake.conv.send(PAM_PROMPT_ECHO_ON, &prompt) {
+                Ok(Some(name)) => match CString::new(name) {
+                    Ok(name) => {
+                        fake.items.lock().unwrap().insert(PAM_USER, name);
+                    }
+                    Err(_) => return PamResultCode::PAM_BUF_ERR,
+                },
+                Ok(None) => return PamResultCode::PAM_CONV_ERR,
+                Err(e) => return e,
+            }
+        }
+        *user = fake.items.lock().unwrap()[&PAM_USER].as_ptr();
+        PamResultCode::PAM_SUCCESS
+    }
+
+    pub unsafe fn pam_putenv(pamh: *const PamHandle, name_value: *const c_char) -> PamResultCode {
+        let name_value = CStr::from_ptr(name_value).to_string_lossy().into_owned();
+        let mut env = fake(pamh).env.lock().unwrap();
+        match name_value.find('=') {
+            Some(0) => PamResultCode::PAM_BAD_ITEM,
+            Some(pos) => {
+                env.insert(String::from(&name_value[..pos]), CString::new(&name_value[pos + 1..]).unwrap());
+                PamResultCode::PAM_SUCCESS
+            }
+            None => match env.remove(&name_value) {
+                Some(_) => PamResultCode::PAM_SUCCESS,
+                None => PamResultCode::PAM_BAD_ITEM,
+            },
+        }
+    }
+
+    pub unsafe fn pam_getenv(pamh: *const PamHandle, name: *const c_char) -> *const c_char {
+        let name = CStr::from_ptr(name).to_string_lossy();
+        fake(pamh).env.lock().unwrap().get(&name[..]).map_or(ptr::null(), |v| v.as_ptr())
+    }
+
+    /// Unlike the real one this isn't variadic: `PamHandle::syslog` always
+    /// passes a single message for the `%s` format.
+    pub unsafe fn pam_syslog(pamh: *const PamHandle,
+                             priority: PamLogPriority,
+                             _fmt: *const c_char,
+                             msg: *const c_char) {
+        fake(pamh).logs.lock().unwrap().push((priority & 0x07, CStr::from_ptr(msg).to_string_lossy().into_owned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conv::Message;
+    use items::{PamAuthTok, PamConv, PamUser};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn items() {
+        let pam = FakePam::new().with_item::<PamUser>("alice");
+        assert_eq!(pam.handle().get_item_str::<PamUser>(), Ok(Some(String::from("alice"))));
+        assert_eq!(pam.handle().get_item_str::<PamAuthTok>(), Ok(None));
+
+        pam.handle().set_item_str::<PamAuthTok>("secret").unwrap();
+        assert_eq!(pam.item::<PamAuthTok>(), Some(String::from("secret")));
+    }
+
+    #[test]
+    fn conversation() {
+        let pam = FakePam::new()
+            .with_response(Response::Text(String::from("123456")))
+            .with_response(Response::Binary(1, vec![2, 3]));
+        let conv = pam.handle().get_item::<PamConv>().unwrap();
+
+        let responses = conv.converse(&[Message::TextInfo("Hello"),
+                                        Message::PromptEchoOff("Code: "),
+                                        Message::BinaryPrompt(7, &[8])]).unwrap();
+        assert_eq!(responses, vec![Response::None, Response::Text(String::from("123456")), Response::Binary(1, vec![2, 3])]);
+        assert_eq!(pam.messages(), vec![SentMessage::TextInfo(String::from("Hello")),
+                                        SentMessage::PromptEchoOff(String::from("Code: ")),
+                                        SentMessage::BinaryPrompt(7, vec![8])]);
+
+        assert_eq!(conv.send(PAM_PROMPT_ECHO_ON, "More: "), Err(PamResultCode::PAM_CONV_ERR));
+    }
+
+    #[test]
+    fn get_user_prompts() {
+        let pam = FakePam::new().with_response(Response::Text(String::from("bob")));
+        assert_eq!(pam.handle().get_user(Some("Who? ")), Ok(String::from("bob")));
+        assert_eq!(pam.handle().get_user(None), Ok(String::from("bob")));
+        assert_eq!(pam.messages(), vec![SentMessage::PromptEchoOn(String::from("Who? "))]);
+    }
+
+    struct Dropped(Rc<Cell<usize>>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn data_is_cleaned_up() {
+        let drops = Rc::new(Cell::new(0));
+        {
+            let pam = FakePam::new();
+            pam.handle().set_data("key", Box::new(Dropped(drops.clone()))).unwrap();
+            assert!(unsafe { pam.handle().get_data::<Dropped>("key") }.is_ok());
+            pam.handle().set_data("key", Box::new(Dropped(drops.clone()))).unwrap();
+            assert_eq!(drops.get(), 1);
+            assert!(pam.has_data("key"));
+            assert!(unsafe { pam.handle().get_data::<Dropped>("other") }.is_err());
+        }
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn environment_and_logs() {
+        let pam = FakePam::new().with_env("LANG", "de_DE.UTF-8");
+        assert_eq!(pam.handle().getenv("LANG"), Some(String::from("de_DE.UTF-8")));
+
+        pam.handle().putenv("WFP=1").unwrap();
+        assert_eq!(pam.env("WFP"), Some(String::from("1")));
+        pam.handle().putenv("WFP").unwrap();
+        assert_eq!(pam.env("WFP"), None);
+
+        pam.handle().syslog(LOG_WARNING, "careful");
+        pam.handle().fail_delay(2000000).unwrap();
+        pam.handle().fail_delay(1000).unwrap();
+        assert_eq!(pam.logs(), vec![(LOG_WARNING, String::from("careful"))]);
+        assert_eq!(pam.fail_delay(), Some(2000000));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::{
+        fs::OpenOptionsExt,
+        net::UnixDatagram,
+    },
+    time::Instant,
+};
+
+use json;
+use libc;
+
+use pam::{
+    module::{PamHandle, PamResult},
+    items::{PamUser, PamService, PamRHost, PamTty},
+};
+
+use log;
+use transport::timestamp;
+
+/// Where audit records are appended to, as JSON lines.
+#[derive(Clone)]
+pub enum Sink {
+    File(String),
+    /// A unix datagram socket, configured as `unix:<path>`.
+    Socket(String),
+}
+
+impl Sink {
+    pub fn parse(value: &str) -> Sink {
+        if value.starts_with("unix:") {
+            Sink::Socket(String::from(&value[5..]))
+        } else {
+            Sink::File(String::from(value))
+        }
+    }
+}
+
+/// The audit record of a single authentication attempt.
+pub struct Attempt {
+    started: Instant,
+    timestamp: u64,
+    pub username: Option<String>,
+    pub service: Option<String>,
+    pub rhost: Option<String>,
+    pub tty: Option<String>,
+    pub challenged: Vec<String>,
+    pub answered: Option<String>,
+    pub decision: Option<&'static str>,
+    pub errors: Vec<String>,
+}
+
+impl Attempt {
+    pub fn new(pamh: &PamHandle) -> Attempt {
+        let mut errors = Vec::new();
+        let (username, service, rhost, tty) = {
+            // Items that can't be read are recorded as errors, not as unset.
+            let mut item = |name: &str, value: PamResult<Option<String>>| value.unwrap_or_else(|e| {
+                errors.push(format!("Could not get {}: {:?}", name, e));
+                None
+            });
+            (item("user", pamh.get_item_str::<PamUser>()),
+             item("service", pamh.get_item_str::<PamService>()),
+             item("rhost", pamh.get_item_str::<PamRHost>()),
+             item("tty", pamh.get_item_str::<PamTty>()))
+        };
+        Attempt {
+            started: Instant::now(),
+            timestamp: timestamp().unwrap_or(0),
+            username,
+            service,
+            rhost,
+            tty,
+            challenged: Vec::new(),
+            answered: None,
+            decision: None,
+            errors,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let latency = self.started.elapsed();
+        json::stringify(object!{
+            "timestamp" => self.timestamp,
+            "user" => self.username.clone(),
+            "service" => self.service.clone(),
+            "rhost" => self.rhost.clone(),
+            "tty" => self.tty.clone(),
+            "challenged" => self.challenged.clone(),
+            "answered" => self.answered.clone(),
+            "decision" => self.decision.unwrap_or("error"),
+            "latency_ms" => latency.as_secs()*1000 + latency.subsec_nanos() as u64 / 1000000,
+            "errors" => self.errors.clone(),
+        })
+    }
+
+    /// Appends the record to the sink. Failures are only logged, they never affect the login.
+    pub fn write(&self, sink: &Sink) {
+        append(sink, &self.to_json());
+    }
+}
+
+/// The audit record of a command received by `wfp listen`.
+pub struct Command {
+    timestamp: u64,
+    pub id: String,
+    pub command: Option<String>,
+    pub session: Option<String>,
+    pub username: Option<String>,
+    pub device: Option<String>,
+    /// Why the command was rejected, if it was.
+    pub error: Option<String>,
+}
+
+impl Command {
+    pub fn new(id: &str) -> Command {
+        Command {
+            timestamp: timestamp().unwrap_or(0),
+            id: String::from(id),
+            command: None,
+            session: None,
+            username: None,
+            device: None,
+            error: None,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        json::stringify(object!{
+            "timestamp" => self.timestamp,
+            "id" => &self.id[..],
+            "command" => self.command.clone(),
+            "session" => self.session.clone(),
+            "user" => self.username.clone(),
+            "device" => self.device.clone(),
+            "decision" => if self.error.is_none() { "executed" } else { "rejected" },
+            "error" => self.error.clone(),
+        })
+    }
+
+    /// Appends the record to the sink. Failures are only logged.
+    pub fn write(&self, sink: &Sink) {
+        append(sink, &self.to_json());
+    }
+}
+
+fn append(sink: &Sink, record: &str) {
+    let line = String::from(record) + "\n";
+    let result = match *sink {
+        Sink::File(ref path) => {
+            // Non-blocking, so a FIFO without a reader can't stall the login.
+            OpenOptions::new().append(true).create(true).mode(0o600).custom_flags(libc::O_NONBLOCK).open(path)
+                .and_then(|mut f| f.write_all(line.as_bytes()))
+        }
+        Sink::Socket(ref path) => {
+            UnixDatagram::unbound()
+                .and_then(|s| s.set_nonblocking(true).map(|_| s))
+                .and_then(|s| s.send_to(line.as_bytes(), path).map(|_| ()))
+        }
+    };
+    if let Err(e) = result {
+        log::warning(&format!("Could not write audit record: {}", e));
+    }
+}
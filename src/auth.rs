@@ -0,0 +1,152 @@
+//! The challenge fan-out and response verification shared by `sm_authenticate` and `wfp test`.
+
+use std::{
+    time::Duration,
+    sync::mpsc::{self, RecvTimeoutError},
+};
+
+use json::JsonValue;
+
+use config::Device;
+use crypto::*;
+use firebase::watch;
+use log;
+use transport::*;
+use worker::*;
+
+/// How long after the timeout of a login its challenges may be purged, so that `wfp gc` doesn't
+/// remove challenges of a login that is still waiting.
+const EXPIRY_GRACE: Duration = Duration::from_secs(60);
+
+/// The device that approved a login.
+pub struct Approval {
+    pub device_id: String,
+    pub device_name: Option<String>,
+    pub challenge: String,
+}
+
+pub enum Decision {
+    Approved(Approval),
+    /// The device with the given id denied the login.
+    Denied(String),
+    /// The device with the given id answered with a signature that doesn't verify.
+    BadSignature(String),
+    Timeout,
+}
+
+impl Decision {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Decision::Approved(_) => "approved",
+            Decision::Denied(_) => "denied",
+            Decision::BadSignature(_) => "bad_signature",
+            Decision::Timeout => "timeout",
+        }
+    }
+
+    pub fn device_id(&self) -> Option<&str> {
+        match *self {
+            Decision::Approved(ref approval) => Some(&approval.device_id),
+            Decision::Denied(ref id) | Decision::BadSignature(ref id) => Some(id),
+            Decision::Timeout => None,
+        }
+    }
+}
+
+/// The challenges of a single login, sent to all devices of the user at once.
+pub struct Challenges {
+    workers: Vec<Worker>,
+    receiver: mpsc::Receiver<Result<Decision, String>>,
+    timeout: Duration,
+}
+
+impl Challenges {
+    /// Sends a challenge to every device and starts collecting their answers. `on_send` is called
+    /// with each device before its challenge is sent.
+    ///
+    /// Each challenge at `c/<device>/<challenge>` comes with its expiry at `e/<device>/<challenge>`.
+    /// Both are removed once the login is decided, and the expiry is written first and removed last
+    /// so that `wfp gc` finds what a crashed host left behind.
+    pub fn send<F>(backend: &Backend, baseurl: &str, devices: Vec<Device>, timeout: Duration, mut on_send: F) -> Result<Challenges, String> where
+        F: FnMut(&Device) {
+        let (response, receiver) = mpsc::channel();
+        let mut workers = Vec::new();
+        let lifetime = timeout + EXPIRY_GRACE;
+        let expiry = timestamp()? + lifetime.as_secs() * 1000 + u64::from(lifetime.subsec_millis());
+
+        for device in devices {
+            match Challenges::send_one(backend, baseurl, device, expiry, &response, &mut on_send) {
+                Ok(worker) => workers.push(worker),
+                Err(e) => {
+                    // Stop the challenges sent so far under one deadline, like `wait` does.
+                    stop_all(workers);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(Challenges {
+            workers,
+            receiver,
+            timeout,
+        })
+    }
+
+    /// Sends the challenge to one device, and starts a worker that passes its answer to `response`.
+    fn send_one<F>(backend: &Backend, baseurl: &str, device: Device, expiry: u64, response: &mpsc::Sender<Result<Decision, String>>, on_send: &mut F) -> Result<Worker, String> where
+        F: FnMut(&Device) {
+        log::debug(&format!("Sending challenge to device {}", device.id));
+        on_send(&device);
+
+        let challenge = random(32)?;
+        let signature = device.own_key.sign(&challenge)?;
+        let url = build_url(baseurl, "c", &device.id, Some(&encode(&challenge)))?;
+        let expiry_url = build_url(baseurl, "e", &device.id, Some(&encode(&challenge)))?;
+
+        send_expiry(backend, expiry_url.clone(), expiry)?;
+        send_challenge(backend, url.clone(), &signature)?;
+
+        let thread_response = response.clone();
+
+        collect_response(backend, url.clone(), vec![url, expiry_url], watch("/", move |value: &JsonValue| -> Result<Option<Decision>, String> {
+            // The device removes the challenge to deny the login.
+            let sig = match value.as_str() {
+                Some(s) => s,
+                None => return Ok(Some(Decision::Denied(device.id.clone()))),
+            };
+
+            // Until then, it's the challenge we sent.
+            if sig == encode(&signature) {
+                return Ok(None);
+            }
+
+            let response = decode(sig)?;
+            if device.other_key.verify(&challenge, &response) {
+                Ok(Some(Decision::Approved(Approval {
+                    device_id: device.id.clone(),
+                    device_name: device.name.clone(),
+                    challenge: encode(&challenge),
+                })))
+            } else {
+                Ok(Some(Decision::BadSignature(device.id.clone())))
+            }
+        }), move |res: Result<Decision, String>| {
+            match thread_response.send(res) {
+                Ok(_) => (),
+                Err(_) => (),
+            }
+        })
+    }
+
+    /// Waits for the first device to answer, or for the timeout to expire. The connections to the
+    /// other devices are closed before returning.
+    pub fn wait(self) -> Result<Decision, String> {
+        let result = match self.receiver.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Ok(Decision::Timeout),
+            Err(e) => Err(e.to_string()),
+        };
+        stop_all(self.workers);
+        result
+    }
+}
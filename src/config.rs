@@ -1,63 +1,309 @@
 use std::{
+    collections::HashMap,
     ffi::CStr,
     fs::{File, OpenOptions},
-    io::{BufRead, BufReader, Write},
+    io::{BufRead, BufReader, ErrorKind, Write},
     time::Duration
 };
-use pam::module::PamHandle;
+use pam::{
+    module::PamHandle,
+    constants::PamResultCode,
+};
 
+use audit::Sink;
 use crypto::*;
+use log;
+use lockout::{Lockout, DEFAULT_STATEDIR};
+use messages::Messages;
 use transport::*;
 
+/// The configuration file shared by the module and `wfp`, overridden by the `config` argument.
+pub const DEFAULT_CONFIG: &str = "/etc/wfp.conf";
+
+/// How the module treats the password (`PAM_AUTHTOK`) before sending the challenges.
+///
+/// The module can't check passwords itself. With `use_first_pass`, a module that does, such as
+/// `pam_unix`, has to come before it as `requisite`, so that a wrong password ends the stack
+/// before any challenge is sent; `wfp doctor` checks this. `try_first_pass` is rejected, as it
+/// would prompt for a password that nothing checks.
+#[derive(Clone, PartialEq)]
+pub enum AuthTok {
+    /// Leave the password to the other modules in the stack.
+    Ignore,
+    /// Require a non-empty password entered for an earlier module.
+    UseFirstPass,
+}
+
 #[derive(Clone)]
 pub struct Options {
     pub keyfile: String,
     pub baseurl: String,
     pub timeout: Duration,
     pub username: String,
+    pub statedir: String,
+    pub lockout: Lockout,
+    pub authtok: AuthTok,
+    pub debug: bool,
+    pub quiet: bool,
+    pub audit: Option<Sink>,
+    pub messages: Messages,
+    pub catalog: Option<String>,
+    /// The service account key file to authenticate to the backend with.
+    pub credentials: Option<String>,
+    /// The user id to sign in as with a custom token, instead of as the service account.
+    pub auth_uid: Option<String>,
+    /// The web API key of the Firebase project, for signing in with a custom token.
+    pub api_key: Option<String>,
 }
 
 impl Options {
     pub fn parse(pamh: &PamHandle, args: Vec<&CStr>) -> Result<Options, String> {
+        let args = args.iter().map(|a| a.to_str().map_err(|e| e.to_string())).collect::<Result<Vec<&str>, String>>()?;
+        let mut options = Options::load(&args)?;
+        let user = pamh.get_user(None).map_err(|e| format!("Invalid pam user found: {:?}", e))?;
+        options.username.push_str(&user);
+        Ok(options)
+    }
+
+    /// Applies the configuration file, then the backend selected with `backend`, then the remaining
+    /// arguments. `config` selects another configuration file, which then has to exist.
+    pub fn load(args: &[&str]) -> Result<Options, String> {
+        let settings = Options::settings(args)?;
+        let settings: Vec<&str> = settings.iter().map(|s| &s[..]).collect();
+        Options::from_args(&settings).map_err(|e| format!("Invalid module arguments: {}", e))
+    }
+
+    /// The audit sink of the arguments, or of the configuration file if it can be read, without
+    /// checking the other settings, so that attempts with invalid settings are audited as well.
+    pub fn audit_sink(args: &[&str]) -> Option<Sink> {
+        let settings = Options::settings(args).unwrap_or_else(|_| args.iter().map(|a| String::from(*a)).collect());
+        settings.iter()
+            .rev()
+            .map(|s| unquote(s))
+            .find(|s| s.starts_with("audit="))
+            .map(|s| Sink::parse(&unquote(&s[6..])))
+    }
+
+    /// The settings of the configuration file and the selected backend, followed by the arguments.
+    fn settings(args: &[&str]) -> Result<Vec<String>, String> {
+        let mut path = None;
+        let mut backend = None;
+        let mut rest = Vec::new();
+        for &arg in args {
+            let unquoted = unquote(arg);
+            if unquoted.starts_with("config=") {
+                path = Some(String::from(&unquoted[7..]));
+            } else if unquoted.starts_with("backend=") {
+                backend = Some(String::from(&unquoted[8..]));
+            } else {
+                rest.push(arg);
+            }
+        }
+
+        let file = ConfigFile::load(path.as_ref().map_or(DEFAULT_CONFIG, |p| &p[..]), path.is_some())?;
+        let mut settings = file.defaults.clone();
+        if let Some(name) = backend.or_else(|| file.backend.clone()) {
+            let backend = file.backends.get(&name).ok_or_else(|| format!("Unknown backend: {}", name))?;
+            settings.extend(backend.iter().cloned());
+        }
+        settings.extend(rest.into_iter().map(String::from));
+        Ok(settings)
+    }
+
+    /// Parses the module arguments, without the user, so `wfp doctor` can check them as well.
+    pub fn from_args(args: &[&str]) -> Result<Options, String> {
         let mut options = Options {
             keyfile: String::new(),
             baseurl: String::new(),
             username: String::new(),
             timeout: Duration::from_secs(30),
+            statedir: String::from(DEFAULT_STATEDIR),
+            lockout: Lockout::new(),
+            authtok: AuthTok::Ignore,
+            debug: false,
+            quiet: false,
+            audit: None,
+            messages: Messages::new(),
+            catalog: None,
+            credentials: None,
+            auth_uid: None,
+            api_key: None,
         };
-        for arg in args {
-            let option = arg.to_str().map_err(|e| e.to_string())?;
-            let split: Vec<&str> = option.split("=").collect();
-            if split.len() != 2 {
-                return Err(format!("Invalid setting: {}", option))
-            }
-            let setting = split[0];
-            let value = split[1];
+        for &arg in args {
+            let arg = unquote(arg);
+            let (setting, value) = match arg.find('=') {
+                Some(pos) => (&arg[..pos], Some(unquote(&arg[pos + 1..]))),
+                None => (&arg[..], None),
+            };
+            let value = || value.clone().ok_or_else(|| format!("Missing value for setting {}", setting));
             match setting {
-                "keyfile" => options.keyfile.push_str(value),
-                "baseurl" => options.baseurl.push_str(value),
-                "timeout" => options.timeout = match value.parse::<u64>() {
-                    Ok(t) => Duration::from_secs(t),
-                    Err(e) => {
-                        println!("Invalid timeout argument {}: {}", value, e);
-                        continue;
+                "use_first_pass" => {
+                    options.authtok = if parse_bool(setting, value().ok())? { AuthTok::UseFirstPass } else { AuthTok::Ignore };
+                }
+                "try_first_pass" => {
+                    return Err(String::from("try_first_pass is not supported, as the module can't check passwords: use use_first_pass after a password module"));
+                }
+                "debug" => options.debug = parse_bool(setting, value().ok())?,
+                "quiet" => options.quiet = parse_bool(setting, value().ok())?,
+                "keyfile" => options.keyfile = value()?,
+                "baseurl" => options.baseurl = value()?,
+                "timeout" => options.timeout = parse_secs(setting, &value()?)?,
+                "audit" => options.audit = Some(Sink::parse(&value()?)),
+                "catalog" => options.catalog = Some(value()?),
+                "credentials" => options.credentials = Some(value()?),
+                "auth_uid" => options.auth_uid = Some(value()?),
+                "api_key" => options.api_key = Some(value()?),
+                "panic" => {
+                    let value = value()?;
+                    if parse_panic_result(&value).is_none() {
+                        return Err(format!("Invalid value for setting panic: {}", value));
                     }
-                },
-                _ => {
-                    println!("Unknown setting: {}", option)
                 }
+                key if Messages::is_key(key) => options.messages.set(key, &value()?),
+                "statedir" => {
+                    options.statedir = value()?;
+                    options.lockout.statedir = options.statedir.clone();
+                }
+                "deny" => {
+                    let value = value()?;
+                    options.lockout.deny = value.parse::<usize>().map_err(|e| format!("Invalid value for setting deny: {}: {}", value, e))?;
+                }
+                "fail_interval" => options.lockout.fail_interval = parse_secs(setting, &value()?)?,
+                "unlock_time" => options.lockout.unlock_time = parse_secs(setting, &value()?)?,
+                _ => return Err(format!("Unknown setting: {}", setting)),
             }
         }
-        let user = pamh.get_user(None).map_err(|e| format!("Invalid pam user found: {:?}", e))?;
-        options.username.push_str(&user);
         Ok(options)
     }
 }
 
+/// The settings of a configuration file.
+///
+/// It contains one setting per line, in the same form as the module arguments, optionally followed
+/// by `[backend <name>]` sections with the settings of a named backend, such as `baseurl`. A
+/// `backend=<name>` line before the first section selects the default backend. Lines starting
+/// with `#` are ignored. `panic` is rejected, as it only takes effect in the module arguments.
+#[derive(Default)]
+pub struct ConfigFile {
+    pub defaults: Vec<String>,
+    pub backend: Option<String>,
+    pub backends: HashMap<String, Vec<String>>,
+}
+
+impl ConfigFile {
+    /// Reads and validates the file. A missing file is empty, unless it is `required`.
+    pub fn load(path: &str, required: bool) -> Result<ConfigFile, String> {
+        match File::open(path) {
+            Ok(f) => ConfigFile::parse(BufReader::new(f)).map_err(|e| format!("{}: {}", path, e)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound && !required => Ok(ConfigFile::default()),
+            Err(e) => Err(format!("Could not open {}: {}", path, e)),
+        }
+    }
+
+    fn parse<R: BufRead>(reader: R) -> Result<ConfigFile, String> {
+        let mut file = ConfigFile::default();
+        let mut section: Option<String> = None;
+        for (i, l) in reader.lines().enumerate() {
+            let line = l.map_err(|e| e.to_string())?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let header: Vec<&str> = line[1..line.len() - 1].split_whitespace().collect();
+                match header[..] {
+                    ["backend", name] if !file.backends.contains_key(name) => {
+                        file.backends.insert(String::from(name), Vec::new());
+                        section = Some(String::from(name));
+                    }
+                    _ => return Err(format!("line {}: invalid section: {}", i + 1, line)),
+                }
+                continue;
+            }
+
+            // Allow spaces around the `=`.
+            let setting = match line.find('=') {
+                Some(pos) => format!("{}={}", line[..pos].trim_right(), line[pos + 1..].trim_left()),
+                None => String::from(line),
+            };
+            // Read by `panic_result`, which only has the arguments once a hook panicked.
+            if setting == "panic" || setting.starts_with("panic=") {
+                return Err(format!("line {}: panic can only be set in the module arguments", i + 1));
+            }
+            match section {
+                Some(ref name) => file.backends.get_mut(name).unwrap().push(setting),
+                None if setting.starts_with("backend=") => file.backend = Some(String::from(&setting[8..])),
+                None => file.defaults.push(setting),
+            }
+        }
+
+        // Check everything now rather than on the first login that uses it.
+        let defaults: Vec<&str> = file.defaults.iter().map(|s| &s[..]).collect();
+        Options::from_args(&defaults)?;
+        for (name, settings) in &file.backends {
+            let settings: Vec<&str> = defaults.iter().cloned().chain(settings.iter().map(|s| &s[..])).collect();
+            Options::from_args(&settings).map_err(|e| format!("backend {}: {}", name, e))?;
+        }
+        if let Some(ref name) = file.backend {
+            if !file.backends.contains_key(name) {
+                return Err(format!("Unknown backend: {}", name));
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// Removes the `[...]` that pam.d uses to quote arguments containing spaces, with `\]` for `]`, in
+/// case it is passed on as is.
+fn unquote(arg: &str) -> String {
+    if arg.len() >= 2 && arg.starts_with('[') && arg.ends_with(']') {
+        arg[1..arg.len() - 1].replace("\\]", "]")
+    } else {
+        String::from(arg)
+    }
+}
+
+/// Flags are enabled by their name alone, or set with `true`/`false`, `yes`/`no`, `on`/`off` or
+/// `1`/`0`.
+fn parse_bool(setting: &str, value: Option<String>) -> Result<bool, String> {
+    match value.as_ref().map(|v| &v[..]) {
+        None | Some("true") | Some("yes") | Some("on") | Some("1") => Ok(true),
+        Some("false") | Some("no") | Some("off") | Some("0") => Ok(false),
+        Some(v) => Err(format!("Invalid value for setting {}: {}", setting, v)),
+    }
+}
+
+fn parse_secs(setting: &str, value: &str) -> Result<Duration, String> {
+    value.parse::<u64>().map(Duration::from_secs).map_err(|e| format!("Invalid value for setting {}: {}: {}", setting, value, e))
+}
+
+/// Maps `panic=system_err|auth_err|ignore` to the result of a hook that panicked.
+fn parse_panic_result(value: &str) -> Option<PamResultCode> {
+    match value {
+        "system_err" => Some(PamResultCode::PAM_SYSTEM_ERR),
+        "auth_err" => Some(PamResultCode::PAM_AUTH_ERR),
+        "ignore" => Some(PamResultCode::PAM_IGNORE),
+        _ => None,
+    }
+}
+
+/// The result of a hook that panicked, without touching anything but the arguments.
+pub fn panic_result(args: &[&CStr]) -> PamResultCode {
+    args.iter()
+        .filter_map(|a| a.to_str().ok())
+        .map(unquote)
+        .filter(|a| a.starts_with("panic="))
+        .filter_map(|a| parse_panic_result(&a[6..]))
+        .last()
+        .unwrap_or(PamResultCode::PAM_SYSTEM_ERR)
+}
+
 #[derive(Clone)]
 pub struct Device {
     pub username: String,
     pub id: String,
+    pub name: Option<String>,
     pub other_key: PubKey,
     pub own_key: PrivKey,
 }
@@ -67,8 +313,8 @@ impl Device {
         let error = |s| Err(format!("Invalid config line: {}={}: {}", username, device, s));
 
         let split: Vec<&str> = device.split(":").collect();
-        if split.len() != 3 {
-            return error("not 3 device parameters");
+        if split.len() != 3 && split.len() != 4 {
+            return error("not 3 or 4 device parameters");
         }
 
         if split[0].len() != 43 {
@@ -78,20 +324,35 @@ impl Device {
         let id = String::from(split[0]);
         let other_key = decode(split[1])?;
         let own_key = decode(split[2])?;
+        let name = match split.get(3) {
+            Some(n) => Some(String::from_utf8(decode(n)?).map_err(|e| e.to_string())?),
+            None => None,
+        };
 
         Ok(Device {
             username: String::from(username),
             id,
+            name,
             other_key: PubKey::from_der(&other_key)?,
             own_key: PrivKey::from_der(&own_key)?,
         })
     }
 
     pub fn fetch_all(keyfile: &str, wanted_user: &str) -> Vec<Device> {
+        Device::read_keyfile(keyfile, |user| wanted_user == user)
+    }
+
+    /// The devices of all users.
+    pub fn fetch_every_user(keyfile: &str) -> Vec<Device> {
+        Device::read_keyfile(keyfile, |_| true)
+    }
+
+    fn read_keyfile<F>(keyfile: &str, wanted: F) -> Vec<Device> where
+        F: Fn(&str) -> bool {
         let file = match File::open(keyfile) {
             Ok(f) => f,
             Err(e) => {
-                println!("Could not open keyfile: {}", e);
+                log::error(&format!("Could not open keyfile: {}", e));
                 return Vec::new()
             }
         };
@@ -102,22 +363,22 @@ impl Device {
             let line = match l {
                 Ok(v) => v,
                 Err(e) => {
-                    println!("Could not read line: {}", e);
+                    log::error(&format!("Could not read line: {}", e));
                     continue
                 }
             };
             let split: Vec<&str> = line.split("=").collect();
             if split.len() != 2 {
-                println!("Invalid config line: {}", line);
+                log::warning(&format!("Invalid config line: {}", line));
                 continue;
             }
             let user = split[0];
             let device = split[1];
-            if wanted_user.eq(user) {
+            if wanted(user) {
                 devices.push(match Device::parse(user, device) {
                     Ok(d) => d,
                     Err(e) => {
-                        println!("{}", e);
+                        log::warning(&e);
                         continue;
                     }
                 });
@@ -126,12 +387,90 @@ impl Device {
         devices
     }
 
+    pub fn find(keyfile: &str, username: &str, id: &str) -> Result<Device, String> {
+        Device::fetch_all(keyfile, username).into_iter().find(|d| d.id == id).ok_or_else(|| format!("Unknown device: {}", id))
+    }
+
     pub fn to_config(&self) -> Result<String, String> {
-        Ok([&self.username, &[&self.id, &encode(&self.other_key.to_der()?)[..], &encode(&self.own_key.to_der()?)[..]].join(":")[..]].join("="))
+        let mut fields = vec![self.id.clone(), encode(&self.other_key.to_der()?), encode(&self.own_key.to_der()?)];
+        if let Some(ref name) = self.name {
+            fields.push(encode(name.as_bytes()));
+        }
+        Ok([&self.username, &fields.join(":")[..]].join("="))
     }
 
     pub fn write(&self, keyfile: &str) -> Result<(), String> {
         let mut file = OpenOptions::new().write(true).append(true).create(true).open(keyfile).map_err(|e| e.to_string())?;
         writeln!(file, "{}", &self.to_config()?).map_err(|e| e.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_may_contain_equals_signs() {
+        let options = Options::from_args(&["baseurl=https://example.firebaseio.com/?auth=abc=", "keyfile=/etc/wfp"]).unwrap();
+        assert_eq!(options.baseurl, "https://example.firebaseio.com/?auth=abc=");
+        assert_eq!(options.keyfile, "/etc/wfp");
+    }
+
+    #[test]
+    fn brackets_are_removed() {
+        let options = Options::from_args(&["[prompt=Approve on {device_name} [y\\]]", "keyfile=[/etc/wfp keys]"]).unwrap();
+        assert_eq!(options.messages.render("prompt", &[("device_name", "phone")]), "Approve on phone [y]");
+        assert_eq!(options.keyfile, "/etc/wfp keys");
+    }
+
+    #[test]
+    fn flags() {
+        let options = Options::from_args(&["debug", "quiet=no", "use_first_pass=yes"]).unwrap();
+        assert!(options.debug);
+        assert!(!options.quiet);
+        assert!(options.authtok == AuthTok::UseFirstPass);
+        assert!(Options::from_args(&["try_first_pass"]).is_err());
+
+        let options = Options::from_args(&["use_first_pass", "use_first_pass=off"]).unwrap();
+        assert!(options.authtok == AuthTok::Ignore);
+    }
+
+    #[test]
+    fn config_file_backends() {
+        let file = ConfigFile::parse(&b"# Shared settings\ntimeout = 60\nbackend=home\n\n[backend home]\nbaseurl=https://home.firebaseio.com\nkeyfile=/etc/wfp/home\n\n[backend work]\nbaseurl=https://work.firebaseio.com\n"[..]).unwrap();
+        assert_eq!(file.defaults, vec!["timeout=60"]);
+        assert_eq!(file.backend, Some(String::from("home")));
+        assert_eq!(file.backends["work"], vec!["baseurl=https://work.firebaseio.com"]);
+
+        assert!(ConfigFile::parse(&b"[backend home]\ntimeout=later\n"[..]).is_err());
+        assert!(ConfigFile::parse(&b"backend=home\n"[..]).is_err());
+        assert!(ConfigFile::parse(&b"[frontend]\n"[..]).is_err());
+        assert!(ConfigFile::parse(&b"panic = ignore\n"[..]).is_err());
+        assert!(ConfigFile::parse(&b"[backend home]\npanic=auth_err\n"[..]).is_err());
+    }
+
+    #[test]
+    fn arguments_override_config_file() {
+        let path = ::std::env::temp_dir().join(format!("wfp-test-{}.conf", ::std::process::id()));
+        ::std::fs::write(&path, "keyfile=/etc/wfp/keys\ntimeout=60\n[backend work]\nbaseurl=https://work.firebaseio.com\n").unwrap();
+        let config = format!("config={}", path.display());
+
+        let options = Options::load(&[&config, "backend=work", "timeout=10"]).unwrap();
+        assert_eq!(options.keyfile, "/etc/wfp/keys");
+        assert_eq!(options.baseurl, "https://work.firebaseio.com");
+        assert_eq!(options.timeout, Duration::from_secs(10));
+        assert!(Options::load(&[&config, "backend=home"]).is_err());
+
+        ::std::fs::remove_file(&path).unwrap();
+        assert!(Options::load(&[&config]).is_err());
+    }
+
+    #[test]
+    fn invalid_settings_fail() {
+        assert!(Options::from_args(&["timeout=soon"]).is_err());
+        assert!(Options::from_args(&["debug=maybe"]).is_err());
+        assert!(Options::from_args(&["keyfile"]).is_err());
+        assert!(Options::from_args(&["panic=abort"]).is_err());
+        assert!(Options::from_args(&["keyfle=/etc/wfp"]).is_err());
+    }
+}
@@ -0,0 +1,438 @@
+//! Authentication of the requests to the backend with the key of a Google service account.
+//!
+//! Without `auth_uid`, the service account signs in with an OAuth2 access token, sent in an
+//! `Authorization: Bearer` header, which grants full access to the database. With it, a custom
+//! token for that user id is exchanged for a Firebase ID token, sent as `auth=` as the backend
+//! doesn't take it in a header, so that the security rules can restrict the host to its own
+//! locations with `auth.uid`. Errors of requests must therefore go through `transport::describe`,
+//! which leaves out the query.
+//!
+//! Tokens are cached until shortly before they expire. Streams are reopened with a new token when
+//! the backend sends `auth_revoked`.
+//!
+//! Each login or command uses the credentials of its own options, through `shared`, so that logins
+//! with different `credentials=` don't see each other's tokens, while logins with the same ones
+//! share them.
+
+use std::{
+    fs::File,
+    io::Read,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use openssl::{
+    hash::MessageDigest,
+    pkey::PKey,
+    sign::Signer,
+};
+use reqwest::{
+    Client as ReqwestClient,
+    ClientBuilder as ReqwestClientBuilder,
+    Url,
+    header::{Authorization, Bearer, ContentType, Headers},
+};
+
+use json::{self, JsonValue};
+
+use config::Options;
+use transport::{describe, encode, timestamp};
+
+const OAUTH_SCOPES: &str = "https://www.googleapis.com/auth/userinfo.email https://www.googleapis.com/auth/firebase.database";
+const CUSTOM_TOKEN_AUDIENCE: &str = "https://identitytoolkit.googleapis.com/google.identity.identitytoolkit.v1.IdentityToolkit";
+const IDENTITY_TOOLKIT: &str = "https://identitytoolkit.googleapis.com";
+const SECURE_TOKEN: &str = "https://securetoken.googleapis.com";
+
+/// Assertions are valid for the maximum of an hour.
+const ASSERTION_LIFETIME: u64 = 3600;
+/// Tokens are replaced this long before they expire, so that they don't expire in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+struct Token {
+    /// Whether it is sent as a bearer token, or as `auth=`.
+    bearer: bool,
+    value: String,
+    expires: Instant,
+}
+
+pub struct Credentials {
+    /// The key file, user id and API key it was loaded with.
+    source: (String, Option<String>, Option<String>),
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+    uid: Option<String>,
+    api_key: Option<String>,
+    /// Where custom tokens are exchanged and ID tokens refreshed, overridden for tests.
+    identity_toolkit: String,
+    secure_token: String,
+    token: Mutex<Option<Token>>,
+    refresh_token: Mutex<Option<String>>,
+}
+
+impl Credentials {
+    /// Reads a service account key file as downloaded from the Firebase console.
+    pub fn load(path: &str, uid: Option<String>, api_key: Option<String>) -> Result<Credentials, String> {
+        let mut contents = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("Could not read credentials {}: {}", path, e))?;
+        let key = json::parse(&contents).map_err(|e| format!("Invalid credentials {}: {}", path, e))?;
+
+        if key["type"] != "service_account" {
+            return Err(format!("Invalid credentials {}: not a service account key", path));
+        }
+        if uid.is_some() && api_key.is_none() {
+            return Err(String::from("auth_uid requires api_key"));
+        }
+        let field = |name: &str| key[name].as_str().map(String::from).ok_or_else(|| format!("Invalid credentials {}: no {}", path, name));
+
+        Ok(Credentials {
+            source: (String::from(path), uid.clone(), api_key.clone()),
+            client_email: field("client_email")?,
+            private_key: field("private_key")?,
+            token_uri: field("token_uri")?,
+            uid,
+            api_key,
+            identity_toolkit: String::from(IDENTITY_TOOLKIT),
+            secure_token: String::from(SECURE_TOKEN),
+            token: Mutex::new(None),
+            refresh_token: Mutex::new(None),
+        })
+    }
+
+    /// Adds a token to `headers`, or to the query of `url` for ID tokens, signing in again if the
+    /// last one is about to expire. Signing in fails once `deadline` passes, if there is one.
+    pub fn authorize(&self, url: &mut Url, headers: &mut Headers, deadline: Option<Instant>) -> Result<(), String> {
+        let token = self.token(deadline)?;
+        if token.bearer {
+            headers.set(Authorization(Bearer { token: token.value }));
+        } else {
+            url.query_pairs_mut().append_pair("auth", &token.value);
+        }
+        Ok(())
+    }
+
+    /// Forgets the current token, after the backend revoked it.
+    pub fn invalidate(&self) {
+        *self.token.lock().unwrap() = None;
+    }
+
+    /// The cached token, or a new one. The lock isn't held while signing in, so that a slow token
+    /// endpoint doesn't block `invalidate`; concurrent callers may sign in twice.
+    fn token(&self, deadline: Option<Instant>) -> Result<Token, String> {
+        if let Some(ref t) = *self.token.lock().unwrap() {
+            if t.expires > Instant::now() + EXPIRY_MARGIN {
+                return Ok(t.clone());
+            }
+        }
+        let new = match self.uid {
+            Some(ref uid) => self.id_token(uid, deadline)?,
+            None => self.access_token(deadline)?,
+        };
+        *self.token.lock().unwrap() = Some(new.clone());
+        Ok(new)
+    }
+
+    /// Signs in as the service account.
+    fn access_token(&self, deadline: Option<Instant>) -> Result<Token, String> {
+        let assertion = self.assertion(object!{
+            "iss" => &self.client_email[..],
+            "scope" => OAUTH_SCOPES,
+            "aud" => &self.token_uri[..],
+        })?;
+        let url = Url::parse(&self.token_uri).map_err(|e| e.to_string())?;
+        let response = post_form(url, &[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &assertion),
+        ], deadline)?;
+        Ok(Token {
+            bearer: true,
+            value: response["access_token"].as_str().map(String::from).ok_or("No access token in response")?,
+            expires: expires(&response["expires_in"])?,
+        })
+    }
+
+    /// Signs in as `uid`, with the refresh token of the last sign in if there is one.
+    fn id_token(&self, uid: &str, deadline: Option<Instant>) -> Result<Token, String> {
+        let api_key = self.api_key.as_ref().map_or("", |k| &k[..]);
+
+        let refresh = self.refresh_token.lock().unwrap().take();
+        if let Some(refresh) = refresh {
+            let mut url = Url::parse(&format!("{}/v1/token", self.secure_token)).map_err(|e| e.to_string())?;
+            url.query_pairs_mut().append_pair("key", api_key);
+            match post_form(url, &[("grant_type", "refresh_token"), ("refresh_token", &refresh)], deadline) {
+                Ok(ref response) if response["id_token"].is_string() => {
+                    *self.refresh_token.lock().unwrap() = response["refresh_token"].as_str().map(String::from);
+                    return Ok(Token {
+                        bearer: false,
+                        value: response["id_token"].as_str().unwrap().to_string(),
+                        expires: expires(&response["expires_in"])?,
+                    });
+                }
+                // Fall back to a new custom token.
+                _ => (),
+            }
+        }
+
+        let custom_token = self.assertion(object!{
+            "iss" => &self.client_email[..],
+            "sub" => &self.client_email[..],
+            "aud" => CUSTOM_TOKEN_AUDIENCE,
+            "uid" => uid,
+        })?;
+        let mut url = Url::parse(&format!("{}/v1/accounts:signInWithCustomToken", self.identity_toolkit)).map_err(|e| e.to_string())?;
+        url.query_pairs_mut().append_pair("key", api_key);
+        let response = post(url, json::stringify(object!{
+            "token" => custom_token,
+            "returnSecureToken" => true,
+        }), deadline)?;
+        *self.refresh_token.lock().unwrap() = response["refreshToken"].as_str().map(String::from);
+        Ok(Token {
+            bearer: false,
+            value: response["idToken"].as_str().map(String::from).ok_or("No ID token in response")?,
+            expires: expires(&response["expiresIn"])?,
+        })
+    }
+
+    /// A JWT with the given claims, valid from now, signed with RS256 by the service account.
+    fn assertion(&self, mut claims: JsonValue) -> Result<String, String> {
+        let now = timestamp()? / 1000;
+        claims["iat"] = now.into();
+        claims["exp"] = (now + ASSERTION_LIFETIME).into();
+
+        let header = json::stringify(object!{ "alg" => "RS256", "typ" => "JWT" });
+        let message = format!("{}.{}", encode(header.as_bytes()), encode(json::stringify(claims).as_bytes()));
+
+        let key = PKey::private_key_from_pem(self.private_key.as_bytes()).map_err(|e| e.to_string())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|e| e.to_string())?;
+        signer.update(message.as_bytes()).map_err(|e| e.to_string())?;
+        let signature = signer.sign_to_vec().map_err(|e| e.to_string())?;
+        Ok(format!("{}.{}", message, encode(&signature)))
+    }
+}
+
+/// `expires_in` is a number for OAuth2 and a string for Firebase Auth.
+fn expires(expires_in: &JsonValue) -> Result<Instant, String> {
+    let secs = expires_in.as_u64()
+        .or_else(|| expires_in.as_str().and_then(|s| s.parse().ok()))
+        .ok_or("No expiry in response")?;
+    Ok(Instant::now() + Duration::from_secs(secs))
+}
+
+/// A client whose requests time out at `deadline`, if there is one.
+fn client(deadline: Option<Instant>) -> Result<ReqwestClient, String> {
+    let deadline = match deadline {
+        Some(d) => d,
+        None => return Ok(ReqwestClient::new()),
+    };
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(String::from("Out of time"));
+    }
+    ReqwestClientBuilder::new().timeout(deadline - now).build().map_err(|e| describe(&e))
+}
+
+fn post_form(url: Url, form: &[(&str, &str)], deadline: Option<Instant>) -> Result<JsonValue, String> {
+    let mut req = client(deadline)?.post(url);
+    req.form(form);
+    read_json(req.send().map_err(|e| describe(&e))?)
+}
+
+fn post(url: Url, body: String, deadline: Option<Instant>) -> Result<JsonValue, String> {
+    let mut req = client(deadline)?.post(url);
+    req.header(ContentType::json());
+    req.body(body);
+    read_json(req.send().map_err(|e| describe(&e))?)
+}
+
+fn read_json(mut res: ::reqwest::Response) -> Result<JsonValue, String> {
+    let mut body = String::new();
+    res.read_to_string(&mut body).map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("Sign in failed: {:?}: {}", res.status(), body));
+    }
+    json::parse(&body).map_err(|e| e.to_string())
+}
+
+/// The credentials loaded so far, so that their tokens are reused by later logins.
+static LOADED: Mutex<Vec<Arc<Credentials>>> = Mutex::new(Vec::new());
+
+/// The credentials of `options`, or none if it has none. They are loaded once per key file, user
+/// id and API key.
+pub fn shared(options: &Options) -> Result<Option<Arc<Credentials>>, String> {
+    let path = match options.credentials {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+    let source = (path.clone(), options.auth_uid.clone(), options.api_key.clone());
+
+    let mut loaded = LOADED.lock().unwrap();
+    if let Some(credentials) = loaded.iter().find(|c| c.source == source) {
+        return Ok(Some(credentials.clone()));
+    }
+    let credentials = Arc::new(Credentials::load(path, source.1, source.2)?);
+    loaded.push(credentials.clone());
+    Ok(Some(credentials))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        env,
+        fs,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use openssl::{rsa::Rsa, sign::Verifier};
+
+    use transport::decode;
+
+    /// Answers one request per response with a JSON body, and returns the requests.
+    fn stub(responses: Vec<JsonValue>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (url, thread::spawn(move || {
+            responses.into_iter().map(|response| {
+                let mut stream = listener.accept().unwrap().0;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.to_lowercase().starts_with("content-length:") {
+                        length = line[15..].trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+
+                let body = json::stringify(response);
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+                request
+            }).collect()
+        }))
+    }
+
+    /// A key file for a new RSA key with the given token endpoint, and the public key.
+    fn key_file(name: &str, token_uri: &str) -> (String, PKey<::openssl::pkey::Public>) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let public = PKey::public_key_from_pem(&rsa.public_key_to_pem().unwrap()).unwrap();
+        let path = env::temp_dir().join(format!("wfp-credentials-{}-{}.json", name, ::std::process::id()));
+        fs::write(&path, json::stringify(object!{
+            "type" => "service_account",
+            "client_email" => "wfp@project.iam.gserviceaccount.com",
+            "private_key" => String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap(),
+            "token_uri" => token_uri,
+        })).unwrap();
+        (path.to_str().unwrap().to_string(), public)
+    }
+
+    /// The claims of a JWT, after checking its signature.
+    fn claims(jwt: &str, key: &PKey<::openssl::pkey::Public>) -> JsonValue {
+        let parts: Vec<&str> = jwt.split('.').collect();
+        assert_eq!(parts.len(), 3);
+        let mut verifier = Verifier::new(MessageDigest::sha256(), key).unwrap();
+        verifier.update(format!("{}.{}", parts[0], parts[1]).as_bytes()).unwrap();
+        assert!(verifier.verify(&decode(parts[2]).unwrap()).unwrap());
+        json::parse(&String::from_utf8(decode(parts[1]).unwrap()).unwrap()).unwrap()
+    }
+
+    fn form_value(request: &str, name: &str) -> String {
+        let body = request.rsplit("\r\n").next().unwrap();
+        ::reqwest::Url::parse(&format!("http://x/?{}", body)).unwrap()
+            .query_pairs().find(|&(ref k, _)| k == name).map(|(_, v)| v.into_owned()).unwrap()
+    }
+
+    #[test]
+    fn access_token() {
+        let (url, server) = stub(vec![object!{ "access_token" => "at1", "expires_in" => 3600, "token_type" => "Bearer" }]);
+        let (path, public) = key_file("oauth", &format!("{}/token", url));
+        let credentials = Credentials::load(&path, None, None).unwrap();
+
+        for _ in 0..2 {
+            let mut db = Url::parse("https://db.example.com/c/d/.json").unwrap();
+            let mut headers = Headers::new();
+            credentials.authorize(&mut db, &mut headers, None).unwrap();
+            assert_eq!(db.query(), None);
+            assert_eq!(headers.get::<Authorization<Bearer>>().map(|a| &a.0.token[..]), Some("at1"));
+        }
+
+        // The token was cached.
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /token "));
+        assert_eq!(form_value(&requests[0], "grant_type"), "urn:ietf:params:oauth:grant-type:jwt-bearer");
+        let claims = claims(&form_value(&requests[0], "assertion"), &public);
+        assert_eq!(claims["iss"], "wfp@project.iam.gserviceaccount.com");
+        assert_eq!(claims["aud"].as_str(), Some(&format!("{}/token", url)[..]));
+        assert_eq!(claims["exp"].as_u64().unwrap() - claims["iat"].as_u64().unwrap(), ASSERTION_LIFETIME);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn id_token() {
+        let (url, server) = stub(vec![
+            object!{ "idToken" => "id1", "refreshToken" => "r1", "expiresIn" => "3600" },
+            object!{ "id_token" => "id2", "refresh_token" => "r2", "expires_in" => "3600" },
+        ]);
+        let (path, public) = key_file("uid", "http://unused.example.com/token");
+        let mut credentials = Credentials::load(&path, Some(String::from("host:1")), Some(String::from("apikey"))).unwrap();
+        credentials.identity_toolkit = url.clone();
+        credentials.secure_token = url.clone();
+
+        let mut first = Url::parse("https://db.example.com/c/d/.json").unwrap();
+        let mut headers = Headers::new();
+        credentials.authorize(&mut first, &mut headers, None).unwrap();
+        assert_eq!(first.query(), Some("auth=id1"));
+        assert_eq!(headers.len(), 0);
+
+        // After auth_revoked, the refresh token is used.
+        credentials.invalidate();
+        let mut second = Url::parse("https://db.example.com/c/d/.json").unwrap();
+        credentials.authorize(&mut second, &mut Headers::new(), None).unwrap();
+        assert_eq!(second.query(), Some("auth=id2"));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /v1/accounts:signInWithCustomToken?key=apikey "));
+        let body = json::parse(requests[0].rsplit("\r\n").next().unwrap()).unwrap();
+        assert_eq!(body["returnSecureToken"], true);
+        let claims = claims(body["token"].as_str().unwrap(), &public);
+        assert_eq!(claims["uid"], "host:1");
+        assert_eq!(claims["aud"], CUSTOM_TOKEN_AUDIENCE);
+        assert!(requests[1].starts_with("POST /v1/token?key=apikey "));
+        assert_eq!(form_value(&requests[1], "refresh_token"), "r1");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shared_per_source() {
+        let (path, _) = key_file("shared", "http://unused.example.com/token");
+        let options = |extra: &str| Options::from_args(&[&format!("credentials={}", path)[..], extra]).unwrap();
+
+        let first = shared(&options("debug")).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&first, &shared(&options("quiet")).unwrap().unwrap()));
+        assert!(shared(&options("auth_uid=host:1")).is_err());
+        let uid = shared(&Options::from_args(&[&format!("credentials={}", path)[..], "auth_uid=host:1", "api_key=key"]).unwrap()).unwrap().unwrap();
+        assert!(!Arc::ptr_eq(&first, &uid));
+        assert!(shared(&Options::from_args(&[]).unwrap()).unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_credentials() {
+        assert!(Credentials::load("/nonexistent", None, None).is_err());
+        let (path, _) = key_file("invalid", "http://unused.example.com/token");
+        assert!(Credentials::load(&path, Some(String::from("host:1")), None).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,617 @@
+//! `wfp doctor`: checks the pam configuration, the keyfile and the backend, and explains what is
+//! wrong, as misconfigurations otherwise only show up as failing logins.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{
+    ClientBuilder,
+    StatusCode,
+    Url,
+    header::{Accept, ContentType, Date, qitem},
+    mime,
+};
+
+use config::{AuthTok, ConfigFile, Device, Options, DEFAULT_CONFIG};
+use lockout::DEFAULT_STATEDIR;
+use transport::{build_url, describe, Backend};
+
+const PAM_DIR: &str = "/etc/pam.d";
+const MODULE: &str = "pam_wfp.so";
+/// Where pam looks for modules given without a path, depending on the distribution.
+const MODULE_DIRS: &[&str] = &[
+    "/lib/security",
+    "/lib64/security",
+    "/lib/x86_64-linux-gnu/security",
+    "/lib/aarch64-linux-gnu/security",
+    "/usr/lib/security",
+    "/usr/lib64/security",
+];
+/// Modules that check the password, which `use_first_pass` relies on.
+const PASSWORD_MODULES: &[&str] = &["pam_unix.so", "pam_sss.so", "pam_ldap.so", "pam_krb5.so", "pam_winbind.so", "pam_userdb.so"];
+/// How deep includes are followed, against include loops.
+const MAX_INCLUDE_DEPTH: usize = 8;
+/// Skew at which signed commands, which are valid for five minutes, start to be rejected.
+const MAX_SKEW: Duration = Duration::from_secs(5 * 60);
+const WARN_SKEW: Duration = Duration::from_secs(30);
+
+struct Report {
+    problems: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, msg: &str) {
+        println!("[ ok ] {}", msg);
+    }
+
+    fn warn(&mut self, msg: &str, hint: &str) {
+        self.warnings += 1;
+        println!("[warn] {}\n       {}", msg, hint);
+    }
+
+    fn fail(&mut self, msg: &str, hint: &str) {
+        self.problems += 1;
+        println!("[FAIL] {}\n       {}", msg, hint);
+    }
+}
+
+pub fn doctor(program: &str, args: &[String]) -> Result<(), String> {
+    let mut keyfile = None;
+    let mut baseurl = None;
+    let mut statedir = None;
+    let mut pamdir = String::from(PAM_DIR);
+
+    let mut flags = args.iter();
+    while let Some(flag) = flags.next() {
+        let value = flags.next().ok_or_else(|| usage(program))?.clone();
+        match &flag[..] {
+            "--keyfile" => keyfile = Some(value),
+            "--baseurl" => baseurl = Some(value),
+            "--pamdir" => pamdir = value,
+            "--statedir" => statedir = Some(value),
+            _ => return Err(usage(program)),
+        }
+    }
+
+    let mut report = Report { problems: 0, warnings: 0 };
+
+    match ConfigFile::load(DEFAULT_CONFIG, false) {
+        Ok(_) => report.ok(&format!("Configuration file {}", DEFAULT_CONFIG)),
+        Err(e) => report.fail(&e, &format!("Fix {}, the module rejects all logins until then", DEFAULT_CONFIG)),
+    }
+
+    // Check what the module is configured with, unless told otherwise.
+    let mut configured = pam_config(&pamdir, &mut report);
+    if configured.is_empty() {
+        configured.extend(Options::load(&[]).ok());
+    }
+    let keyfiles = keyfile.map_or_else(|| unique(configured.iter().map(|o| &o.keyfile)), |k| vec![k]);
+    let baseurls = baseurl.map_or_else(|| unique(configured.iter().map(|o| &o.baseurl)), |b| vec![b]);
+    let mut statedirs = statedir.map_or_else(|| unique(configured.iter().map(|o| &o.statedir)), |s| vec![s]);
+    if statedirs.is_empty() {
+        statedirs.push(String::from(DEFAULT_STATEDIR));
+    }
+
+    if keyfiles.is_empty() {
+        report.fail("No keyfile to check", &format!("Pass --keyfile <keyfile>, or configure keyfile= in {}", DEFAULT_CONFIG));
+    }
+    for keyfile in &keyfiles {
+        check_keyfile(keyfile, &mut report);
+    }
+    for statedir in &statedirs {
+        check_statedir(statedir, &mut report);
+    }
+    // The backend is checked with the credentials of the module, if it has any.
+    let backend = match configured.iter().find(|o| o.credentials.is_some()) {
+        Some(options) => check_credentials(options, &mut report),
+        None => Some(Backend::default()),
+    };
+    if baseurls.is_empty() {
+        report.fail("No backend to check", &format!("Pass --baseurl <baseurl>, or configure baseurl= in {}", DEFAULT_CONFIG));
+    }
+    for baseurl in &baseurls {
+        check_backend(baseurl, backend.as_ref(), &mut report);
+    }
+
+    match report.problems {
+        0 => {
+            println!("No problems found.");
+            Ok(())
+        }
+        1 => Err(String::from("1 problem found.")),
+        n => Err(format!("{} problems found.", n)),
+    }
+}
+
+/// The distinct values that are set, in order.
+fn unique<'a, I: Iterator<Item = &'a String>>(values: I) -> Vec<String> {
+    let mut unique: Vec<String> = Vec::new();
+    for value in values {
+        if !value.is_empty() && !unique.contains(value) {
+            unique.push(value.clone());
+        }
+    }
+    unique
+}
+
+fn usage(program: &str) -> String {
+    format!("Usage: {} doctor [--keyfile <keyfile>] [--baseurl <baseurl>] [--pamdir <dir>] [--statedir <dir>]", program)
+}
+
+/// Splits a pam.d line into fields, keeping `[...]` together as pam does, with `\]` for a literal
+/// `]`.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut brackets = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '[' if !brackets && token.is_empty() => brackets = true,
+            '\\' if brackets && chars.peek() == Some(&']') => token.push(chars.next().unwrap()),
+            ']' if brackets => brackets = false,
+            c if c.is_whitespace() && !brackets => {
+                if !token.is_empty() {
+                    tokens.push(token.clone());
+                    token.clear();
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// The lines of a pam.d file, split into fields, along with their line numbers.
+fn read_pam_file(path: &Path) -> io::Result<Vec<(usize, Vec<String>)>> {
+    let file = File::open(path)?;
+    let mut lines = Vec::new();
+    let mut pending = String::new();
+    for (i, l) in BufReader::new(file).lines().enumerate() {
+        let line = match l {
+            Ok(l) => l,
+            Err(_) => break,
+        };
+        // Lines ending with a backslash continue on the next one.
+        if line.ends_with('\\') {
+            pending.push_str(&line[..line.len() - 1]);
+            pending.push(' ');
+            continue;
+        }
+        pending.push_str(&line);
+        let tokens = tokenize(pending.split('#').next().unwrap_or(""));
+        pending.clear();
+        if !tokens.is_empty() {
+            lines.push((i + 1, tokens));
+        }
+    }
+    Ok(lines)
+}
+
+/// The control of the auth line that checks the password, if `tokens` is one or includes one.
+fn password_module(dir: &Path, tokens: &[String], depth: usize) -> Option<String> {
+    if tokens[0] == "@include" {
+        return tokens.get(1).and_then(|f| included_password_module(dir, f, depth));
+    }
+    if tokens.len() < 3 || tokens[0].trim_left_matches('-') != "auth" {
+        return None;
+    }
+    match &tokens[1][..] {
+        "include" => included_password_module(dir, &tokens[2], depth),
+        // A requisite module only ends the substack, not the stack.
+        "substack" => included_password_module(dir, &tokens[2], depth).map(|_| tokens[1].clone()),
+        control => {
+            let name = Path::new(&tokens[2]).file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if PASSWORD_MODULES.contains(&name) { Some(String::from(control)) } else { None }
+        }
+    }
+}
+
+fn included_password_module(dir: &Path, file: &str, depth: usize) -> Option<String> {
+    if depth >= MAX_INCLUDE_DEPTH {
+        return None;
+    }
+    let lines = read_pam_file(&dir.join(file)).ok()?;
+    lines.iter().filter_map(|&(_, ref tokens)| password_module(dir, tokens, depth + 1)).next()
+}
+
+/// Finds the lines of the pam configuration that use the module and checks them.
+fn pam_config(dir: &str, report: &mut Report) -> Vec<Options> {
+    let mut files: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect(),
+        Err(e) => {
+            report.fail(&format!("Could not read {}: {}", dir, e), "Run wfp doctor as root, or pass --pamdir");
+            return Vec::new();
+        }
+    };
+    files.sort();
+
+    let mut found = Vec::new();
+    for path in files {
+        let lines = match read_pam_file(&path) {
+            Ok(l) => l,
+            Err(e) => {
+                report.warn(&format!("Could not read {}: {}", path.display(), e), "Run wfp doctor as root");
+                continue;
+            }
+        };
+
+        // The control of the first module before the current line that checks the password.
+        let mut password = None;
+        for (number, tokens) in lines {
+            if tokens.len() >= 3 && Path::new(&tokens[2]).file_name().map_or(false, |n| n == MODULE) {
+                let location = format!("{}:{}", path.display(), number);
+                if let Some(options) = check_pam_line(&location, &tokens, password.as_ref().map(|c: &String| &c[..]), report) {
+                    found.push(options);
+                }
+            } else if password.is_none() {
+                password = password_module(Path::new(dir), &tokens, 0);
+            }
+        }
+    }
+
+    if found.is_empty() {
+        report.warn(&format!("{} is not used in {}", MODULE, dir),
+                    &format!("Add `auth required {} keyfile=<keyfile> baseurl=<baseurl>` to the pam configuration of a service", MODULE));
+    }
+    found
+}
+
+/// Checks a line using the module, where `password` is the control of the module checking the
+/// password before it, if any.
+fn check_pam_line(location: &str, tokens: &[String], password: Option<&str>, report: &mut Report) -> Option<Options> {
+    let kind = tokens[0].trim_left_matches('-');
+    match kind {
+        "auth" | "session" => (),
+        _ => report.warn(&format!("{}: {} is used as a {} module", location, MODULE, kind),
+                         "It only takes part in auth and session, use one of those"),
+    }
+
+    let module = &tokens[2];
+    let exists = if module.starts_with('/') {
+        Path::new(module).is_file()
+    } else {
+        MODULE_DIRS.iter().any(|d| Path::new(d).join(module).is_file())
+    };
+    if !exists {
+        report.fail(&format!("{}: {} not found", location, module),
+                    &format!("Install {} into the security module directory of the system, such as /lib/security", MODULE));
+    }
+
+    let args: Vec<&str> = tokens[3..].iter().map(|a| &a[..]).collect();
+    let options = match Options::load(&args) {
+        Ok(o) => o,
+        Err(e) => {
+            report.fail(&format!("{}: {}", location, e), &format!("Fix the module arguments or {}", DEFAULT_CONFIG));
+            return None;
+        }
+    };
+    if kind == "auth" {
+        if options.keyfile.is_empty() {
+            report.fail(&format!("{}: no keyfile configured", location), &format!("Add keyfile=<keyfile> to the module arguments or {}", DEFAULT_CONFIG));
+        }
+        if options.baseurl.is_empty() {
+            report.fail(&format!("{}: no baseurl configured", location), &format!("Add baseurl=<url of the database> to the module arguments or {}", DEFAULT_CONFIG));
+        }
+        if options.authtok == AuthTok::UseFirstPass {
+            match password {
+                None => report.fail(&format!("{}: use_first_pass, but no module before it checks the password", location),
+                                    "Add a password module such as `auth requisite pam_unix.so` before it, the module doesn't check passwords"),
+                Some("requisite") => (),
+                Some(control) if control.split_whitespace().any(|c| c == "default=die") => (),
+                Some(control) => report.warn(&format!("{}: the password module before it is {}", location, control),
+                                             "Challenges are sent for wrong passwords as well, make it requisite"),
+            }
+        }
+    }
+    report.ok(&format!("{}: {} {} {}", location, tokens[0], tokens[1], module));
+    Some(options)
+}
+
+fn check_keyfile(keyfile: &str, report: &mut Report) {
+    let metadata = match fs::metadata(keyfile) {
+        Ok(m) => m,
+        Err(e) => {
+            report.fail(&format!("Keyfile {}: {}", keyfile, e), "Pair a device with `wfp <baseurl> <keyfile> <username> <device-name>`");
+            return;
+        }
+    };
+    if metadata.mode() & 0o077 != 0 {
+        report.warn(&format!("Keyfile {} is accessible by other users (mode {:o})", keyfile, metadata.mode() & 0o777),
+                    &format!("It contains private keys, run `chmod 600 {}`", keyfile));
+    }
+    if metadata.uid() != 0 {
+        report.warn(&format!("Keyfile {} is owned by uid {}", keyfile, metadata.uid()),
+                    &format!("Anyone who can write it can pair devices, run `chown root: {}`", keyfile));
+    }
+
+    let file = match File::open(keyfile) {
+        Ok(f) => f,
+        Err(e) => {
+            report.fail(&format!("Could not open keyfile {}: {}", keyfile, e), "Run wfp doctor as root, the module runs as root as well");
+            return;
+        }
+    };
+
+    let mut users = HashSet::new();
+    let mut ids = HashSet::new();
+    let mut devices = 0;
+    for (i, l) in BufReader::new(file).lines().enumerate() {
+        let line = match l {
+            Ok(l) => l,
+            Err(e) => {
+                report.fail(&format!("Could not read keyfile {}: {}", keyfile, e), "Check the file system");
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let location = format!("{}:{}", keyfile, i + 1);
+        let pos = match line.find('=') {
+            Some(p) => p,
+            None => {
+                report.fail(&format!("{}: not of the form <user>=<device>", location), "Remove the line and pair the device again");
+                continue;
+            }
+        };
+        let (user, device) = (&line[..pos], &line[pos + 1..]);
+        match Device::parse(user, device) {
+            Ok(d) => {
+                if !ids.insert(d.id.clone()) {
+                    report.warn(&format!("{}: device {} is paired more than once", location, d.id), "Remove all but one of its lines");
+                }
+                users.insert(String::from(user));
+                devices += 1;
+            }
+            // The error contains the line, which has private keys in it.
+            Err(_) => report.fail(&format!("{}: invalid device of {}", location, user),
+                                  "Ids are 43 characters, keys base64url DER, remove the line and pair the device again"),
+        }
+    }
+
+    if devices == 0 {
+        report.fail(&format!("Keyfile {} has no devices", keyfile), "Pair a device with `wfp <baseurl> <keyfile> <username> <device-name>`");
+    } else {
+        report.ok(&format!("Keyfile {}: {} device(s) of {} user(s)", keyfile, devices, users.len()));
+    }
+}
+
+fn check_statedir(statedir: &str, report: &mut Report) {
+    match fs::metadata(statedir) {
+        Ok(ref m) if !m.is_dir() => report.fail(&format!("State directory {} is not a directory", statedir), "Remove it or configure statedir="),
+        Ok(ref m) if m.mode() & 0o022 != 0 => {
+            report.warn(&format!("State directory {} is writable by other users (mode {:o})", statedir, m.mode() & 0o777),
+                        &format!("They could reset lockouts or forge sessions, run `chmod 700 {}`", statedir));
+        }
+        Ok(_) => report.ok(&format!("State directory {}", statedir)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => report.ok(&format!("State directory {} will be created on first use", statedir)),
+        Err(e) => report.fail(&format!("Could not check state directory {}: {}", statedir, e), "Run wfp doctor as root"),
+    }
+}
+
+/// Opens the stream of a challenge like the module does, and compares the clock with the backend.
+/// Signs in with the credentials of `options`, and returns the backend to check with them unless
+/// that failed.
+fn check_credentials(options: &Options, report: &mut Report) -> Option<Backend> {
+    let path = options.credentials.clone().unwrap_or_default();
+    let backend = match Backend::new(options) {
+        Ok(b) => b,
+        Err(e) => {
+            report.fail(&e, "Set credentials= to the service account key downloaded from the Firebase console, and api_key= along with auth_uid=");
+            return None;
+        }
+    };
+    let mut url = Url::parse("https://example.com/").unwrap();
+    match backend.authorize(&mut url) {
+        Ok(_) => {
+            report.ok(&format!("Signed in with credentials {}", path));
+            Some(backend)
+        }
+        Err(e) => {
+            report.fail(&format!("Could not sign in with credentials {}: {}", path, e),
+                        "Check that the service account and api_key belong to the project, and that the clock is synchronized");
+            None
+        }
+    }
+}
+
+fn check_backend(baseurl: &str, backend: Option<&Backend>, report: &mut Report) {
+    let mut url = match build_url(baseurl, "c", "doctor", None) {
+        Ok(u) => u,
+        Err(e) => {
+            report.fail(&format!("Invalid baseurl {}: {}", baseurl, e), "Use the url of the database, such as https://<project>.firebaseio.com");
+            return;
+        }
+    };
+    if url.scheme() != "https" {
+        report.warn(&format!("Backend {} is not using https", baseurl), "Challenges and answers can be seen and blocked on the network");
+    }
+    let headers = match backend.map(|b| b.authorize(&mut url)) {
+        Some(Ok(headers)) => headers,
+        // Already reported
+        _ => return,
+    };
+
+    let client = match ClientBuilder::new().timeout(Duration::from_secs(10)).build() {
+        Ok(c) => c,
+        Err(e) => {
+            report.fail(&format!("Could not create http client: {}", e), "Check the TLS setup of the system");
+            return;
+        }
+    };
+    let mut request = client.get(url);
+    request.headers(headers);
+    request.header(Accept(vec![qitem(mime::TEXT_EVENT_STREAM)]));
+    let response = match request.send() {
+        Ok(r) => r,
+        Err(e) => {
+            report.fail(&format!("Could not reach backend {}: {}", baseurl, describe(&e)), "Check baseurl, DNS, proxies and firewalls");
+            return;
+        }
+    };
+
+    match response.status() {
+        StatusCode::Unauthorized | StatusCode::Forbidden => {
+            report.fail(&format!("Backend {} denied access: {}", baseurl, response.status()), "Check the security rules of the database for c/, e/, i/, s/ and h/, and credentials=");
+            return;
+        }
+        status if !status.is_success() => {
+            report.fail(&format!("Backend {} answered with {}", baseurl, status), "Check that baseurl is the url of the database");
+            return;
+        }
+        _ => (),
+    }
+
+    match response.headers().get::<ContentType>() {
+        Some(&ContentType(ref m)) if m.type_() == mime::TEXT && m.subtype() == mime::EVENT_STREAM => {
+            report.ok(&format!("Backend {} streams events", baseurl));
+        }
+        other => report.fail(&format!("Backend {} answered with content type {} instead of text/event-stream", baseurl,
+                                      other.map_or(String::from("none"), |c| c.to_string())),
+                             "Check that baseurl is the url of a Firebase database and no proxy rewrites the response"),
+    }
+
+    let date = match response.headers().get::<Date>() {
+        Some(&Date(date)) => SystemTime::from(date),
+        None => {
+            report.warn(&format!("Backend {} didn't send its time", baseurl), "Make sure the clock is synchronized, for example with NTP");
+            return;
+        }
+    };
+    let skew = match SystemTime::now().duration_since(date) {
+        Ok(d) => d,
+        Err(e) => e.duration(),
+    };
+    let msg = format!("Clock is off by {} seconds from backend {}", skew.as_secs(), baseurl);
+    if skew > MAX_SKEW {
+        report.fail(&msg, "Signed commands from devices are rejected, synchronize the clock with NTP");
+    } else if skew > WARN_SKEW {
+        report.warn(&msg, "Synchronize the clock with NTP");
+    } else {
+        report.ok(&msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn report() -> Report {
+        Report { problems: 0, warnings: 0 }
+    }
+
+    fn tokens(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn tokenize_pam_lines() {
+        assert_eq!(tokenize(" auth\trequired  pam_wfp.so keyfile=/etc/wfp "), vec!["auth", "required", "pam_wfp.so", "keyfile=/etc/wfp"]);
+        assert_eq!(tokenize("auth [success=1 default=ignore] pam_wfp.so"), vec!["auth", "success=1 default=ignore", "pam_wfp.so"]);
+        assert_eq!(tokenize("auth required pam_wfp.so [prompt=Approve [\\]here\\]]"), vec!["auth", "required", "pam_wfp.so", "prompt=Approve []here]"]);
+        assert_eq!(tokenize("a[b] c"), vec!["a[b]", "c"]);
+        assert!(tokenize("  ").is_empty());
+    }
+
+    #[test]
+    fn check_lines() {
+        let dir = env::temp_dir().join(format!("wfp-doctor-line-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let module = dir.join(MODULE);
+        File::create(&module).unwrap();
+        let module = module.display();
+
+        let mut r = report();
+        let options = check_pam_line("sshd:1", &tokens(&format!("-auth required {} config=/dev/null keyfile=/etc/wfp baseurl=https://db", module)), None, &mut r).unwrap();
+        assert_eq!((options.keyfile.as_str(), options.baseurl.as_str()), ("/etc/wfp", "https://db"));
+        assert_eq!((r.problems, r.warnings), (0, 0));
+
+        let mut r = report();
+        assert!(check_pam_line("sshd:1", &tokens(&format!("account required {} config=/dev/null", module)), None, &mut r).is_some());
+        assert_eq!((r.problems, r.warnings), (0, 1));
+
+        // Session lines don't need a keyfile or baseurl, auth lines do.
+        let mut r = report();
+        assert!(check_pam_line("sshd:1", &tokens(&format!("session optional {} config=/dev/null", module)), None, &mut r).is_some());
+        assert_eq!(r.problems, 0);
+        assert!(check_pam_line("sshd:1", &tokens(&format!("auth required {} config=/dev/null", module)), None, &mut r).is_some());
+        assert_eq!(r.problems, 2);
+
+        let mut r = report();
+        assert!(check_pam_line("sshd:1", &tokens(&format!("auth required {} config=/dev/null bogus=1", module)), None, &mut r).is_none());
+        assert_eq!(r.problems, 1);
+
+        let mut r = report();
+        assert!(check_pam_line("sshd:1", &tokens("session optional /nonexistent/pam_wfp.so config=/dev/null"), None, &mut r).is_some());
+        assert_eq!(r.problems, 1);
+
+        // use_first_pass needs a password module before it, which should end the stack on failure.
+        let line = tokens(&format!("auth required {} config=/dev/null keyfile=/etc/wfp baseurl=https://db use_first_pass", module));
+        let mut r = report();
+        assert!(check_pam_line("sshd:1", &line, None, &mut r).is_some());
+        assert_eq!((r.problems, r.warnings), (1, 0));
+        let mut r = report();
+        assert!(check_pam_line("sshd:1", &line, Some("requisite"), &mut r).is_some());
+        assert!(check_pam_line("sshd:1", &line, Some("success=ok default=die"), &mut r).is_some());
+        assert_eq!((r.problems, r.warnings), (0, 0));
+        assert!(check_pam_line("sshd:1", &line, Some("required"), &mut r).is_some());
+        assert_eq!((r.problems, r.warnings), (0, 1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finds_module_in_pam_config() {
+        let dir = env::temp_dir().join(format!("wfp-doctor-pam-{}", process::id()));
+        let pamdir = dir.join("pam.d");
+        fs::create_dir_all(&pamdir).unwrap();
+        let module = dir.join(MODULE);
+        File::create(&module).unwrap();
+        fs::write(pamdir.join("sshd"), format!("\
+# auth required {module} config=/dev/null
+auth required pam_unix.so
+-auth [success=done default=die] {module} \\
+    config=/dev/null keyfile=/etc/wfp \\
+    baseurl=https://db # keyfile=/etc/other
+session optional {module} config=/dev/null
+", module = module.display())).unwrap();
+
+        let mut r = report();
+        let found = pam_config(pamdir.to_str().unwrap(), &mut r);
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].keyfile.as_str(), found[0].baseurl.as_str()), ("/etc/wfp", "https://db"));
+        assert_eq!((r.problems, r.warnings), (0, 0));
+
+        // The password module can come from an included file, and has to come first.
+        fs::write(pamdir.join("common-auth"), "auth requisite pam_unix.so nullok\n").unwrap();
+        fs::write(pamdir.join("sshd"), format!("\
+@include common-auth
+auth required {module} config=/dev/null keyfile=/etc/wfp baseurl=https://db use_first_pass
+", module = module.display())).unwrap();
+        fs::write(pamdir.join("su"), format!("\
+auth required {module} config=/dev/null keyfile=/etc/wfp baseurl=https://db use_first_pass
+auth include common-auth
+", module = module.display())).unwrap();
+        let mut r = report();
+        assert_eq!(pam_config(pamdir.to_str().unwrap(), &mut r).len(), 2);
+        assert_eq!((r.problems, r.warnings), (1, 0));
+
+        let mut r = report();
+        assert!(pam_config(dir.join("missing").to_str().unwrap(), &mut r).is_empty());
+        assert_eq!(r.problems, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+//! The Firebase streaming protocol: `put` and `patch` events applied to a local copy of the
+//! location that is listened to.
+
+use std::{
+    cell::RefCell,
+    fmt,
+};
+
+use json::{self, JsonValue};
+
+/// A stream event that ends the stream.
+#[derive(Debug, PartialEq)]
+pub enum StreamError {
+    /// The security rules no longer allow reading the location.
+    Cancelled,
+    /// The credentials of the stream expired or were revoked.
+    AuthRevoked,
+    /// An event that doesn't follow the protocol.
+    Invalid(String),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Cancelled => write!(f, "Cancel received, exiting due to modified permissions..."),
+            StreamError::AuthRevoked => write!(f, "Authentication revoked"),
+            StreamError::Invalid(ref e) => write!(f, "Invalid event: {}", e),
+        }
+    }
+}
+
+impl From<StreamError> for String {
+    fn from(e: StreamError) -> String {
+        e.to_string()
+    }
+}
+
+/// A location whose value was set, with `Null` if it was removed.
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    /// The path relative to the location of the stream, `/` for the location itself.
+    pub path: String,
+    pub value: JsonValue,
+}
+
+/// The value of the location of a stream, as far as it was sent.
+///
+/// As in the database, there are no `null` members and no empty objects: writing `null`
+/// removes a value, and objects left empty are removed as well.
+pub struct Tree {
+    root: JsonValue,
+}
+
+impl Default for Tree {
+    fn default() -> Tree {
+        Tree::new()
+    }
+}
+
+impl Tree {
+    pub fn new() -> Tree {
+        Tree {
+            root: JsonValue::Null,
+        }
+    }
+
+    /// The value at `path`, `Null` if there is none.
+    pub fn get(&self, path: &str) -> &JsonValue {
+        split(path).iter().fold(&self.root, |node, key| &node[*key])
+    }
+
+    /// Applies an event of the stream, returning the locations it changed.
+    pub fn apply(&mut self, event: &str, data: &str) -> Result<Vec<Change>, StreamError> {
+        match event {
+            "put" | "patch" => (),
+            "cancel" => return Err(StreamError::Cancelled),
+            "auth_revoked" => return Err(StreamError::AuthRevoked),
+            // keep-alive and events of later versions of the protocol
+            _ => return Ok(Vec::new()),
+        }
+
+        let d = json::parse(data).map_err(|e| StreamError::Invalid(e.to_string()))?;
+        let path = d["path"].as_str().ok_or_else(|| StreamError::Invalid(format!("{} without path", event)))?;
+        let mut path = split(path);
+
+        let mut changes = Vec::new();
+        if event == "put" {
+            changes.push((path, d["data"].clone()));
+        } else {
+            if !d["data"].is_object() {
+                return Err(StreamError::Invalid(String::from("patch without object")));
+            }
+            for (key, value) in d["data"].entries() {
+                path.push(key);
+                changes.push((path.clone(), value.clone()));
+                path.pop();
+            }
+        }
+
+        Ok(changes.into_iter().map(|(path, value)| {
+            set(&mut self.root, &path, normalize(value));
+            Change {
+                path: format!("/{}", path.join("/")),
+                value: self.get(&path.join("/")).clone(),
+            }
+        }).collect())
+    }
+}
+
+fn split(path: &str) -> Vec<&str> {
+    path.split('/').filter(|k| !k.is_empty()).collect()
+}
+
+/// Whether a change at `changed` can affect the value at `watched`, as it is at, above or below
+/// it.
+fn related(changed: &str, watched: &str) -> bool {
+    split(changed).iter().zip(split(watched).iter()).all(|(a, b)| a == b)
+}
+
+fn set(node: &mut JsonValue, path: &[&str], value: JsonValue) {
+    match path.split_first() {
+        None => *node = value,
+        Some((key, rest)) => {
+            if value.is_null() && !node.has_key(key) {
+                return;
+            }
+            // Indexing turns anything but an object into one.
+            set(&mut node[*key], rest, value);
+            if node[*key].is_null() {
+                node.remove(key);
+            }
+            if node.is_object() && node.is_empty() {
+                *node = JsonValue::Null;
+            }
+        }
+    }
+}
+
+/// Removes `null` members and empty objects.
+fn normalize(value: JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Object(_) => {
+            let mut result = JsonValue::new_object();
+            for (key, value) in value.entries() {
+                let value = normalize(value.clone());
+                if !value.is_null() {
+                    result[key] = value;
+                }
+            }
+            if result.is_empty() { JsonValue::Null } else { result }
+        }
+        value => value,
+    }
+}
+
+/// Adapts `predicate` to a `data_callback` of `transport::collect_response`: the events are
+/// applied to a `Tree`, and after every change at, above or below `path` the predicate is called
+/// with the value there. Its result is returned until it decides. `cancel` and `auth_revoked` fail.
+pub fn watch<P, R>(path: &str, predicate: P) -> impl Fn(String, String) -> Result<Option<R>, String> where
+    P: Fn(&JsonValue) -> Result<Option<R>, String> {
+    let path = path.to_string();
+    let tree = RefCell::new(Tree::new());
+    move |event: String, data: String| {
+        let mut tree = tree.borrow_mut();
+        if !tree.apply(&event, &data)?.iter().any(|change| related(&change.path, &path)) {
+            return Ok(None);
+        }
+        predicate(tree.get(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn apply(tree: &mut Tree, event: &str, path: &str, data: JsonValue) -> Result<Vec<Change>, StreamError> {
+        tree.apply(event, &json::stringify(object!{ "path" => path, "data" => data }))
+    }
+
+    fn change(path: &str, value: JsonValue) -> Change {
+        Change { path: String::from(path), value }
+    }
+
+    #[test]
+    fn put() {
+        let mut tree = Tree::new();
+        assert_eq!(apply(&mut tree, "put", "/", object!{ "a" => object!{ "b" => 1 } }).unwrap(), vec![change("/", object!{ "a" => object!{ "b" => 1 } })]);
+        assert_eq!(apply(&mut tree, "put", "/a/c", "x".into()).unwrap(), vec![change("/a/c", "x".into())]);
+        assert_eq!(tree.get("/"), &object!{ "a" => object!{ "b" => 1, "c" => "x" } });
+        assert_eq!(tree.get("a/c"), &JsonValue::from("x"));
+        assert!(tree.get("/a/d/e").is_null());
+
+        // A value is replaced by an object when writing below it.
+        apply(&mut tree, "put", "/a/b/c", 2.into()).unwrap();
+        assert_eq!(tree.get("/a/b"), &object!{ "c" => 2 });
+    }
+
+    #[test]
+    fn delete() {
+        let mut tree = Tree::new();
+        apply(&mut tree, "put", "/", object!{ "a" => object!{ "b" => object!{ "c" => 1 } }, "d" => 2 }).unwrap();
+        assert_eq!(apply(&mut tree, "put", "/a/b/c", JsonValue::Null).unwrap(), vec![change("/a/b/c", JsonValue::Null)]);
+        assert_eq!(tree.get("/"), &object!{ "d" => 2 });
+        assert_eq!(apply(&mut tree, "put", "/x/y", JsonValue::Null).unwrap(), vec![change("/x/y", JsonValue::Null)]);
+        assert_eq!(tree.get("/"), &object!{ "d" => 2 });
+        apply(&mut tree, "put", "/", JsonValue::Null).unwrap();
+        assert!(tree.get("/").is_null());
+
+        apply(&mut tree, "put", "/", object!{ "a" => JsonValue::Null, "b" => object!{}, "c" => object!{ "d" => JsonValue::Null } }).unwrap();
+        assert!(tree.get("/").is_null());
+    }
+
+    #[test]
+    fn patch() {
+        let mut tree = Tree::new();
+        apply(&mut tree, "put", "/", object!{ "a" => object!{ "b" => 1, "c" => 2 } }).unwrap();
+        assert_eq!(apply(&mut tree, "patch", "/a", object!{ "c" => 3, "b" => JsonValue::Null, "d" => object!{ "e" => 4 } }).unwrap(), vec![
+            change("/a/c", 3.into()),
+            change("/a/b", JsonValue::Null),
+            change("/a/d", object!{ "e" => 4 }),
+        ]);
+        assert_eq!(tree.get("/"), &object!{ "a" => object!{ "c" => 3, "d" => object!{ "e" => 4 } } });
+        assert!(apply(&mut tree, "patch", "/", 1.into()).is_err());
+    }
+
+    #[test]
+    fn control_events() {
+        let mut tree = Tree::new();
+        assert_eq!(tree.apply("keep-alive", "null"), Ok(Vec::new()));
+        assert_eq!(tree.apply("cancel", "null"), Err(StreamError::Cancelled));
+        assert_eq!(tree.apply("auth_revoked", "\"credential is no longer valid\""), Err(StreamError::AuthRevoked));
+        assert!(tree.apply("put", "{").is_err());
+        assert!(tree.apply("put", "{\"data\": 1}").is_err());
+    }
+
+    #[test]
+    fn watch_value() {
+        let callback = watch("/s", |value| Ok(value.as_str().map(String::from)));
+        assert_eq!(callback(String::from("put"), String::from("{\"path\":\"/\",\"data\":{\"p\":\"key\"}}")), Ok(None));
+        assert_eq!(callback(String::from("keep-alive"), String::from("null")), Ok(None));
+        assert_eq!(callback(String::from("patch"), String::from("{\"path\":\"/\",\"data\":{\"s\":\"sig\"}}")), Ok(Some(String::from("sig"))));
+        assert!(callback(String::from("cancel"), String::from("null")).is_err());
+    }
+
+    #[test]
+    fn watch_related_changes() {
+        assert!(related("/", "/s"));
+        assert!(related("/s", "/s/"));
+        assert!(related("/s/t", "s"));
+        assert!(!related("/p", "/s"));
+        assert!(!related("/st", "/s"));
+
+        let calls = Cell::new(0);
+        let callback = watch("/s", |_| -> Result<Option<()>, String> {
+            calls.set(calls.get() + 1);
+            Ok(None)
+        });
+        callback(String::from("put"), String::from("{\"path\":\"/\",\"data\":{\"p\":\"key\"}}")).unwrap();
+        callback(String::from("put"), String::from("{\"path\":\"/p\",\"data\":\"other\"}")).unwrap();
+        callback(String::from("patch"), String::from("{\"path\":\"/\",\"data\":{\"p\":\"key\"}}")).unwrap();
+        callback(String::from("put"), String::from("{\"path\":\"/s/t\",\"data\":1}")).unwrap();
+        assert_eq!(calls.get(), 2);
+    }
+}
@@ -0,0 +1,76 @@
+//! `wfp gc`: removes the challenges that hosts left on the backend, such as when they crashed
+//! during a login, using the expiry written with each of them.
+
+use std::collections::HashSet;
+
+use json::JsonValue;
+
+use config::{Device, Options};
+use log;
+use transport::*;
+
+/// Removes the expired challenges of the devices in the keyfile.
+pub fn gc(options: &Options) -> Result<(), String> {
+    let backend = Backend::new(options)?;
+    let now = timestamp()?;
+
+    let mut seen = HashSet::new();
+    let (mut removed, mut failed) = (0, 0);
+    for device in Device::fetch_every_user(&options.keyfile) {
+        if !seen.insert(device.id.clone()) {
+            continue;
+        }
+        let index = match build_url(&options.baseurl, "e", &device.id, None).and_then(|url| fetch(&backend, url)) {
+            Ok(index) => index,
+            Err(e) => {
+                log::warning(&format!("Could not list the challenges of device {}: {}", device.id, e));
+                failed += 1;
+                continue;
+            }
+        };
+        for challenge in expired(&index, now) {
+            // The expiry goes last, so that a challenge that couldn't be removed is found again.
+            let result = build_url(&options.baseurl, "c", &device.id, Some(&challenge))
+                .and_then(|url| remove(&backend, url))
+                .and_then(|_| build_url(&options.baseurl, "e", &device.id, Some(&challenge)))
+                .and_then(|url| remove(&backend, url));
+            match result {
+                Ok(_) => removed += 1,
+                Err(e) => {
+                    log::warning(&format!("Could not remove challenge {} of device {}: {}", challenge, device.id, e));
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("Removed {} expired challenges", removed);
+    if failed > 0 {
+        return Err(format!("Could not purge {} challenges or devices", failed));
+    }
+    Ok(())
+}
+
+/// The challenges of an `e/<device>` index that expired before `now`. Entries that aren't
+/// timestamps are treated as expired.
+fn expired(index: &JsonValue, now: u64) -> Vec<String> {
+    index.entries()
+        .filter(|&(_, expiry)| match expiry.as_u64() {
+            Some(e) => e < now,
+            None => true,
+        })
+        .map(|(challenge, _)| challenge.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_challenges() {
+        let index = object!{ "a" => 1000, "b" => 3000, "c" => "garbage" };
+        assert_eq!(expired(&index, 2000), vec![String::from("a"), String::from("c")]);
+        assert!(expired(&JsonValue::Null, 2000).is_empty());
+    }
+}
@@ -1,36 +1,50 @@
 extern crate base64;
 extern crate eventsource;
+extern crate futures;
+#[macro_use]
 extern crate json;
+extern crate libc;
 extern crate openssl;
 extern crate qrcode;
 #[macro_use]
 extern crate pam;
 extern crate reqwest;
+extern crate tokio_core;
 
+mod audit;
+mod auth;
 mod config;
+mod credentials;
 mod crypto;
+mod firebase;
+mod lockout;
+mod log;
+mod messages;
+mod session;
 mod transport;
 mod worker;
 
 use std::{
+    collections::HashMap,
     ffi::CStr,
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
-    thread,
-    sync::mpsc,
+    process,
 };
 
-use reqwest::Client as ReqwestClient;
-
 use pam::{
-    conv::PamConv,
+    conv::{PamConv, Message},
+    items::{PamAuthTok, PamService, PamTty, PamRHost},
     module::{PamHandle, PamHooks},
-    constants::{PamFlag, PamResultCode, PamResultCode::*, PamMessageStyle, PAM_TEXT_INFO, PAM_ERROR_MSG},
+    constants::{PamFlag, PamResultCode, PamResultCode::*, PamMessageStyle, PAM_TEXT_INFO, PAM_ERROR_MSG, PAM_DELETE_CRED, PAM_SILENT},
 };
 
+use audit::Attempt;
+use auth::*;
 use config::*;
 use crypto::*;
+use lockout::Status;
+use messages::Messages;
+use session::*;
 use transport::*;
-use worker::*;
 
 trait Transformable {
     fn encode(&self) -> Vec<u8>;
@@ -58,125 +72,402 @@ impl Transformable for u64 {
     }
 }
 
+/// Why a login failed.
+enum Failure {
+    /// A message for the user, such as that the login was denied.
+    Refused(String),
+    /// An error, which is only logged, as it may reveal the setup to whoever is at the prompt.
+    Error(String),
+}
+
+impl From<String> for Failure {
+    fn from(e: String) -> Failure {
+        Failure::Error(e)
+    }
+}
+
+/// Key under which the device that approved the login is stored with `set_data`.
+const APPROVAL_DATA: &str = "wfp_approval";
+
+impl Approval {
+    /// Exports the approving device into the environment of the session.
+    fn export(pamh: &PamHandle) -> PamResultCode {
+        let approval = match unsafe { pamh.get_data::<Approval>(APPROVAL_DATA) } {
+            Ok(a) => a,
+            Err(_) => return PAM_IGNORE,
+        };
+
+        let mut vars = vec![format!("WFP_DEVICE_ID={}", approval.device_id)];
+        if let Some(ref name) = approval.device_name {
+            vars.push(format!("WFP_DEVICE_NAME={}", name));
+        }
+        for var in vars {
+            if let Err(e) = pamh.putenv(&var) {
+                return e;
+            }
+        }
+        PAM_SUCCESS
+    }
+}
+
 struct PamImplementation;
 pam_hooks!(PamImplementation);
 
 impl PamHooks for PamImplementation {
     /// This function performs the task of authenticating the user.
     fn sm_authenticate(pamh: &PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        let _log = log::attach(pamh);
         let conv = match pamh.get_item::<PamConv>() {
             Ok(c) => c,
             Err(_) => return PAM_AUTH_ERR,
         };
 
+        let silent = flags & PAM_SILENT != 0;
         let print = |style: PamMessageStyle, msg: &str| -> Result<Option<String>, String> {
+            if silent && (style == PAM_TEXT_INFO || style == PAM_ERROR_MSG) {
+                return Ok(None);
+            }
             conv.send(style, msg).map_err(|e| format!("Pam error: {:?}", e))
         };
 
-        match || -> Result<PamResultCode, String> {
-            let options = Options::parse(pamh, args)?;
+        let mut attempt = Attempt::new(pamh);
+        let mut audit = None;
+        let mut messages = Messages::new();
+
+        let result = || -> Result<PamResultCode, Failure> {
+            let mut options = match Options::parse(pamh, args.clone()) {
+                Ok(options) => options,
+                Err(e) => {
+                    let args: Vec<&str> = args.iter().filter_map(|a| a.to_str().ok()).collect();
+                    audit = Options::audit_sink(&args);
+                    return Err(e.into());
+                }
+            };
+            audit = options.audit.clone();
+            attempt.username = Some(options.username.clone());
+            log::configure(options.debug, options.quiet);
+            let backend = Backend::new(&options)?;
+
+            if let Some(catalog) = options.catalog.clone() {
+                // Not the environment of the application, which belongs to the user under su or sudo.
+                let locale = ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+                    .filter_map(|v| pamh.getenv(v))
+                    .find(|l| !l.is_empty());
+                if let Some(locale) = locale {
+                    options.messages.load_catalog(&catalog, &locale);
+                }
+            }
+            let host = hostname();
+            let timeout_secs = options.timeout.as_secs().to_string();
+            messages = options.messages.clone();
+
+            match options.lockout.status(&options.username)? {
+                Status::Unlocked => (),
+                Status::Locked(Some(remaining)) => {
+                    attempt.decision = Some("locked");
+                    return Err(Failure::Refused(options.messages.render("locked_msg", &[("remaining", &remaining.as_secs().to_string())])));
+                }
+                Status::Locked(None) => {
+                    attempt.decision = Some("locked");
+                    return Err(Failure::Refused(options.messages.render("unlock_msg", &[])));
+                }
+            }
+
+            if options.authtok == AuthTok::UseFirstPass {
+                // Checked by the password module before this one, see `AuthTok`.
+                let authtok = pamh.get_item_str::<PamAuthTok>().map_err(|e| format!("Could not get password: {:?}", e))?;
+                if authtok.unwrap_or_default().is_empty() {
+                    return Err(Failure::Refused(options.messages.render("no_password_msg", &[])));
+                }
+            }
+
             let devices = Device::fetch_all(&options.keyfile, &options.username);
             if devices.len() == 0 {
                 return Ok(PAM_AUTH_ERR);
             }
 
-            let (response, receiver) = mpsc::channel();
-
-            let mut workers = Vec::new();
-
-            let client = ReqwestClient::new();
-
-            for device in devices {
-                print(PAM_TEXT_INFO, &format!("Processing device: {}", device.id))?;
-
-                let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
-                let timestamp = now.as_secs()*1000 + now.subsec_nanos() as u64 / 1000000;
-
-                let challenge = random(32)?;
-                let signature = device.own_key.sign(&challenge)?;
-
-                let message = random(32)?;
-                let url = build_url(&options.baseurl, "c", &device.id, Some(&encode(&challenge)))?;
-
-                send_challenge(&client, url.clone(), &signature)?;
-
-                let thread_response = response.clone();
-
-                workers.push(collect_response(url, options.timeout, false, move |event: String, data: String| -> Result<Option<PamResultCode>, String> {
-                    match &event[..] {
-                        "put" => {
-                            let d = json::parse(&data).map_err(|e| e.to_string())?;
-                            if let Some(path) = d["path"].as_str() {
-                                let data = &d["data"];
-
-                                if path != "/" {
-                                    return Err(format!("Put received with strange path: {}", path))
-                                }
-
-                                let sig = match data.as_str() {
-                                    Some(s) => s,
-                                    None => return Err(String::from("Aborted")),
-                                };
-
-                                if sig != encode(&signature) {
-                                    let response = decode(sig)?;
-                                    if device.other_key.verify(&challenge, &response) {
-                                        return Ok(Some(PAM_SUCCESS));
-                                    } else {
-                                        return Err(String::from("Bad signature"));
-                                    }
-                                }
-                            }
-                        }
-                        "patch" => {
-                            // Should never happen as we don't modify data children
-                            return Err(format!("Patch received, but we normally don't modify data children..."));
-                        }
-                        "keep-alive" => (),
-                        "cancel" => {
-                            return Err(format!("Cancel received, exiting due to modified permissions..."));
-                        }
-                        "auth_revoked" => (), // Ignore as we're not using auth
-                        _ => (), // Ignore the else case as well
-                    }
-                    Ok(None)
-                }, move |res: Result<PamResultCode, String>| {
-                    match thread_response.send(res) {
-                        Ok(_) => (),
-                        Err(_) => (),
-                    }
-                }));
+            let mut names = HashMap::new();
+            let mut prompts = Vec::new();
+            let challenges = Challenges::send(&backend, &options.baseurl, devices, options.timeout, |device| {
+                attempt.challenged.push(device.id.clone());
+                let name = device.name.clone().unwrap_or_else(|| String::from("your device"));
+                prompts.push(options.messages.render("prompt", &[("device_name", &name), ("host", &host), ("timeout", &timeout_secs)]));
+                names.insert(device.id.clone(), name);
+            })?;
+
+            if !silent {
+                let messages: Vec<Message> = prompts.iter().map(|p| Message::TextInfo(p)).collect();
+                conv.converse(&messages).map_err(|e| format!("Pam error: {:?}", e))?;
             }
 
-            let (timeout_sender, timeout_receiver) = mpsc::channel();
-            workers.push(Worker {
-                thread: thread::spawn(move || {
-                    let elapsed = Instant::now();
-                    loop {
-                        if let Ok(_) = timeout_receiver.try_recv() {
-                            return;
-                        }
-                        if elapsed.elapsed() > options.timeout {
-                            break;
-                        }
-                        thread::sleep(Duration::from_millis(100));
-                    }
-                    match response.send(Ok(PAM_AUTH_ERR)) {
-                        Ok(_) => (),
-                        Err(_) => (),
-                    };
-                }),
-                sender: timeout_sender,
-                cleanup: None,
-            });
-
-            receiver.recv().map_err(|e| e.to_string())?
-        }() {
+            let decision = challenges.wait()?;
+            attempt.decision = Some(decision.as_str());
+            attempt.answered = decision.device_id().map(String::from);
+            if let Decision::Approved(approval) = decision {
+                log::info(&format!("Login of {} approved by device {}", options.username, approval.device_id));
+                if let Err(e) = options.lockout.reset(&options.username) {
+                    log::warning(&format!("Could not reset failed approvals: {}", e));
+                }
+                if let Err(e) = pamh.set_data(APPROVAL_DATA, Box::new(approval)) {
+                    log::warning(&format!("Could not store approving device: {:?}", e));
+                }
+                return Ok(PAM_SUCCESS);
+            }
+
+            if let Err(e) = options.lockout.record_failure(&options.username) {
+                log::warning(&format!("Could not record failed approval: {}", e));
+            }
+            match decision {
+                Decision::Denied(id) => {
+                    let name = names.get(&id).map(|n| &n[..]).unwrap_or("your device");
+                    Err(Failure::Refused(options.messages.render("denied_msg", &[("device_name", name), ("host", &host), ("timeout", &timeout_secs)])))
+                }
+                Decision::BadSignature(_) => Err(Failure::Error(String::from("Bad signature"))),
+                _ => {
+                    print(PAM_ERROR_MSG, &options.messages.render("timeout_msg", &[("host", &host), ("timeout", &timeout_secs)]))?;
+                    Ok(PAM_AUTH_ERR)
+                }
+            }
+        }();
+
+        let code = match result {
             Ok(r) => r,
-            Err(e) => {
-                print(PAM_ERROR_MSG, &e);
+            Err(failure) => {
+                let e = match failure {
+                    Failure::Refused(msg) => {
+                        print(PAM_ERROR_MSG, &msg);
+                        msg
+                    }
+                    Failure::Error(e) => {
+                        print(PAM_ERROR_MSG, &messages.render("error_msg", &[]));
+                        e
+                    }
+                };
+                log::error(&format!("Login of {} failed: {}", attempt.username.clone().unwrap_or_default(), e));
+                attempt.errors.push(e);
                 PAM_AUTH_ERR
             }
+        };
+        if let Some(ref sink) = audit {
+            attempt.write(sink);
+        }
+        code
+    }
+
+    /// Honors `panic=`, so admins can decide whether a bug in the module locks users out.
+    fn panic_result(_pamh: &PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        panic_result(&args)
+    }
+
+    /// Exports the approving device as `WFP_DEVICE_ID` and `WFP_DEVICE_NAME`, and removes them
+    /// again with `PAM_DELETE_CRED`.
+    fn sm_setcred(pamh: &PamHandle, _args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+        if flags & PAM_DELETE_CRED != 0 {
+            for name in &["WFP_DEVICE_ID", "WFP_DEVICE_NAME"] {
+                // A name without a value removes the variable, which fails if it isn't set.
+                match pamh.putenv(name) {
+                    Ok(_) | Err(PAM_BAD_ITEM) => (),
+                    Err(e) => return e,
+                }
+            }
+            return PAM_SUCCESS;
+        }
+        Approval::export(pamh)
+    }
+
+    /// Exports the approving device for applications that only pick up the environment when
+    /// opening the session, and tells the device that the session started.
+    fn sm_open_session(pamh: &PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        let _log = log::attach(pamh);
+        let result = Approval::export(pamh);
+        if result != PAM_SUCCESS {
+            return result;
+        }
+
+        match || -> Result<(), String> {
+            let approval = unsafe { pamh.get_data::<Approval>(APPROVAL_DATA) }.map_err(|e| format!("Pam error: {:?}", e))?;
+            let options = Options::parse(pamh, args)?;
+            log::configure(options.debug, options.quiet);
+            let device = Device::find(&options.keyfile, &options.username, &approval.device_id)?;
+
+            let session = Session {
+                id: encode(&random(16)?),
+                device_id: device.id.clone(),
+                challenge: approval.challenge.clone(),
+                username: options.username.clone(),
+                host: hostname(),
+                service: pamh.get_item_str::<PamService>().map_err(|e| format!("Could not get service: {:?}", e))?,
+                tty: pamh.get_item_str::<PamTty>().map_err(|e| format!("Could not get tty: {:?}", e))?,
+                rhost: pamh.get_item_str::<PamRHost>().map_err(|e| format!("Could not get rhost: {:?}", e))?,
+                pid: process::id(),
+                logind: pamh.getenv("XDG_SESSION_ID"),
+                started: timestamp()?,
+            };
+            session.publish(&Backend::new(&options)?, &options.baseurl, &device, None)?;
+            session.register(&options.statedir)?;
+            pamh.set_data(SESSION_DATA, Box::new(session)).map_err(|e| format!("Pam error: {:?}", e))
+        }() {
+            Ok(_) => (),
+            Err(e) => log::warning(&format!("Could not publish session start: {}", e)),
+        }
+        PAM_SUCCESS
+    }
+
+    /// Tells the device that approved the session that it ended.
+    fn sm_close_session(pamh: &PamHandle, args: Vec<&CStr>, _flags: PamFlag) -> PamResultCode {
+        let _log = log::attach(pamh);
+        let session = match unsafe { pamh.get_data::<Session>(SESSION_DATA) } {
+            Ok(s) => s,
+            Err(_) => return PAM_IGNORE,
+        };
+
+        match || -> Result<(), String> {
+            let options = Options::parse(pamh, args)?;
+            log::configure(options.debug, options.quiet);
+            session.unregister(&options.statedir)?;
+            let device = Device::find(&options.keyfile, &options.username, &session.device_id)?;
+            session.publish(&Backend::new(&options)?, &options.baseurl, &device, Some(timestamp()?))
+        }() {
+            Ok(_) => (),
+            Err(e) => log::warning(&format!("Could not publish session end: {}", e)),
+        }
+        PAM_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        env,
+        ffi::CString,
+        fs,
+        ops::Deref,
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use pam::{
+        constants::LOG_ERR,
+        items::PamUser,
+        testing::{FakePam, SentMessage},
+    };
+
+    /// Module arguments, with a state directory of their own that is removed when they are
+    /// dropped.
+    struct Args {
+        statedir: PathBuf,
+        args: Vec<CString>,
+    }
+
+    impl Deref for Args {
+        type Target = [CString];
+
+        fn deref(&self) -> &[CString] {
+            &self.args
         }
     }
+
+    impl Drop for Args {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.statedir);
+        }
+    }
+
+    /// Module arguments without a configuration file or paired devices.
+    fn args(extra: &[&str]) -> Args {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let statedir = env::temp_dir().join(format!("wfp-test-{}-{}", process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+        let mut args = vec![
+            String::from("config=/dev/null"),
+            String::from("keyfile=/nonexistent/wfp-keys"),
+            format!("statedir={}", statedir.display()),
+        ];
+        args.extend(extra.iter().map(|a| String::from(*a)));
+        Args { statedir, args: args.into_iter().map(|a| CString::new(a).unwrap()).collect() }
+    }
+
+    fn authenticate(pam: &FakePam, args: &[CString], flags: PamFlag) -> PamResultCode {
+        PamImplementation::sm_authenticate(pam.handle(), args.iter().map(|a| a.as_c_str()).collect(), flags)
+    }
+
+    #[test]
+    fn fails_without_devices() {
+        let pam = FakePam::new().with_item::<PamUser>("alice");
+        assert_eq!(authenticate(&pam, &args(&[]), 0), PAM_AUTH_ERR);
+        assert!(pam.messages().is_empty());
+        assert!(pam.logs().iter().any(|&(p, ref m)| p == LOG_ERR && m.starts_with("Could not open keyfile")));
+    }
+
+    #[test]
+    fn use_first_pass_requires_password() {
+        let pam = FakePam::new().with_item::<PamUser>("alice");
+        assert_eq!(authenticate(&pam, &args(&["use_first_pass", "no_password_msg=Password first"]), 0), PAM_AUTH_ERR);
+        assert_eq!(pam.messages(), vec![SentMessage::ErrorMsg(String::from("Password first"))]);
+
+        let pam = FakePam::new().with_item::<PamUser>("alice");
+        assert_eq!(authenticate(&pam, &args(&["use_first_pass"]), PAM_SILENT), PAM_AUTH_ERR);
+        assert!(pam.messages().is_empty());
+    }
+
+    #[test]
+    fn empty_password_is_refused() {
+        let pam = FakePam::new()
+            .with_item::<PamUser>("alice")
+            .with_item::<PamAuthTok>("");
+        assert_eq!(authenticate(&pam, &args(&["use_first_pass"]), 0), PAM_AUTH_ERR);
+        assert_eq!(pam.messages(), vec![SentMessage::ErrorMsg(Messages::new().render("no_password_msg", &[]))]);
+    }
+
+    #[test]
+    fn try_first_pass_is_rejected() {
+        let pam = FakePam::new().with_item::<PamUser>("alice");
+        assert_eq!(authenticate(&pam, &args(&["try_first_pass"]), 0), PAM_AUTH_ERR);
+        assert!(pam.messages().iter().all(|m| match *m {
+            SentMessage::PromptEchoOff(_) => false,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn errors_are_only_logged() {
+        let pam = FakePam::new().with_item::<PamUser>("alice");
+        assert_eq!(authenticate(&pam, &args(&["bogus=1"]), 0), PAM_AUTH_ERR);
+        assert_eq!(pam.messages(), vec![SentMessage::ErrorMsg(Messages::new().render("error_msg", &[]))]);
+        assert!(pam.logs().iter().any(|&(p, ref m)| p == LOG_ERR && m.contains("Unknown setting: bogus")));
+    }
+
+    #[test]
+    fn invalid_settings_are_audited() {
+        let args = args(&["bogus=1"]);
+        let path = args.statedir.with_extension("audit");
+        let audit = CString::new(format!("audit={}", path.display())).unwrap();
+        let args: Vec<CString> = args.iter().cloned().chain(Some(audit)).collect();
+
+        let pam = FakePam::new().with_item::<PamUser>("alice");
+        assert_eq!(authenticate(&pam, &args, 0), PAM_AUTH_ERR);
+        let record = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(record.contains("\"decision\":\"error\"") && record.contains("Unknown setting: bogus"));
+    }
+
+    #[test]
+    fn setcred_exports_approving_device() {
+        let pam = FakePam::new();
+        assert_eq!(PamImplementation::sm_setcred(pam.handle(), vec![], 0), PAM_IGNORE);
+
+        pam.handle().set_data(APPROVAL_DATA, Box::new(Approval {
+            device_id: String::from("device"),
+            device_name: Some(String::from("Phone")),
+            challenge: String::from("challenge"),
+        })).unwrap();
+        assert_eq!(PamImplementation::sm_setcred(pam.handle(), vec![], 0), PAM_SUCCESS);
+        assert_eq!(pam.env("WFP_DEVICE_ID"), Some(String::from("device")));
+        assert_eq!(pam.env("WFP_DEVICE_NAME"), Some(String::from("Phone")));
+
+        assert_eq!(PamImplementation::sm_setcred(pam.handle(), vec![], PAM_DELETE_CRED), PAM_SUCCESS);
+        assert_eq!(pam.env("WFP_DEVICE_ID"), None);
+        assert_eq!(pam.env("WFP_DEVICE_NAME"), None);
+        assert_eq!(PamImplementation::sm_setcred(pam.handle(), vec![], PAM_DELETE_CRED), PAM_SUCCESS);
+    }
 }
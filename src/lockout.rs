@@ -0,0 +1,212 @@
+use std::{
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    os::unix::{fs::{DirBuilderExt, OpenOptionsExt}, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libc;
+
+pub const DEFAULT_STATEDIR: &str = "/var/lib/wfp";
+
+pub enum Status {
+    Unlocked,
+    /// Locked for the given remaining time, or until an admin unlocks the user if `None`.
+    Locked(Option<Duration>),
+}
+
+/// Faillock-style counter of failed approvals, persisted per user below `statedir`.
+///
+/// Once `deny` failures happened within `fail_interval` of each other, no further challenges
+/// are sent until `unlock_time` has passed since the last failure. An `unlock_time` of zero keeps
+/// the user locked until `wfp unlock <user>` is run, a `deny` of zero disables the lockout.
+#[derive(Clone)]
+pub struct Lockout {
+    pub statedir: String,
+    pub deny: usize,
+    pub fail_interval: Duration,
+    pub unlock_time: Duration,
+}
+
+impl Lockout {
+    pub fn new() -> Lockout {
+        Lockout {
+            statedir: String::from(DEFAULT_STATEDIR),
+            deny: 3,
+            fail_interval: Duration::from_secs(900),
+            unlock_time: Duration::from_secs(600),
+        }
+    }
+
+    fn path(&self, username: &str) -> Result<PathBuf, String> {
+        if username.is_empty() || username.starts_with('.') || username.contains('/') {
+            return Err(format!("Refusing to track failures for user: {}", username));
+        }
+        Ok(Path::new(&self.statedir).join("faillock").join(username))
+    }
+
+    /// Takes the lock on the failures of `username`, waiting for other updates of them to finish.
+    /// Logins only hold it to check the lockout and to record their outcome, not while waiting
+    /// for the devices, which would hold up other logins and `wfp unlock` of the user.
+    pub fn lock(&self, username: &str) -> Result<Tally, String> {
+        let path = self.path(username)?;
+        if self.deny == 0 {
+            return Ok(Tally { lockout: self.clone(), path, _lock: None });
+        }
+
+        let dir = path.parent().expect("faillock file without directory");
+        DirBuilder::new().recursive(true).mode(0o700).create(dir).map_err(|e| format!("Could not create state directory: {}", e))?;
+        // Usernames can't start with a dot, so this doesn't clash with a faillock file.
+        let file = OpenOptions::new().write(true).create(true).truncate(false).mode(0o600).open(dir.join(format!(".{}.lock", username))).map_err(|e| format!("Could not open faillock lock: {}", e))?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                break;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(format!("Could not lock faillock file: {}", e));
+            }
+        }
+        Ok(Tally { lockout: self.clone(), path, _lock: Some(file) })
+    }
+
+    pub fn status(&self, username: &str) -> Result<Status, String> {
+        self.lock(username)?.status()
+    }
+
+    pub fn record_failure(&self, username: &str) -> Result<(), String> {
+        self.lock(username)?.record_failure()
+    }
+
+    pub fn reset(&self, username: &str) -> Result<(), String> {
+        self.lock(username)?.reset()
+    }
+}
+
+/// The failures of one user, locked against other updates until dropped.
+pub struct Tally {
+    lockout: Lockout,
+    path: PathBuf,
+    _lock: Option<File>,
+}
+
+impl Tally {
+    fn read(&self) -> Result<Vec<u64>, String> {
+        let file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Could not open faillock file: {}", e)),
+        };
+
+        let mut failures = Vec::new();
+        for l in BufReader::new(file).lines() {
+            let line = l.map_err(|e| format!("Could not read faillock file: {}", e))?;
+            match line.trim().parse::<u64>() {
+                Ok(t) => failures.push(t),
+                Err(_) => return Err(format!("Invalid faillock line: {}", line)),
+            }
+        }
+        Ok(failures)
+    }
+
+    /// Replaces the faillock file through a temporary file, so that it is never seen half
+    /// written.
+    fn write(&self, failures: &[u64]) -> Result<(), String> {
+        let temp = self.path.with_file_name(format!(".{}.tmp", self.path.file_name().and_then(|n| n.to_str()).unwrap_or_default()));
+        let mut file = OpenOptions::new().write(true).truncate(true).create(true).mode(0o600).open(&temp).map_err(|e| format!("Could not open faillock file: {}", e))?;
+        for failure in failures {
+            writeln!(file, "{}", failure).map_err(|e| format!("Could not write faillock file: {}", e))?;
+        }
+        file.sync_all().map_err(|e| format!("Could not write faillock file: {}", e))?;
+        fs::rename(&temp, &self.path).map_err(|e| format!("Could not replace faillock file: {}", e))
+    }
+
+    pub fn status(&self) -> Result<Status, String> {
+        let lockout = &self.lockout;
+        if lockout.deny == 0 {
+            return Ok(Status::Unlocked);
+        }
+
+        let failures = self.read()?;
+        let last = match failures.iter().max() {
+            Some(l) => *l,
+            None => return Ok(Status::Unlocked),
+        };
+        let window = lockout.fail_interval.as_secs();
+        if failures.iter().filter(|t| last.saturating_sub(**t) <= window).count() < lockout.deny {
+            return Ok(Status::Unlocked);
+        }
+
+        if lockout.unlock_time.as_secs() == 0 {
+            return Ok(Status::Locked(None));
+        }
+        let until = last + lockout.unlock_time.as_secs();
+        let now = now()?;
+        if until > now {
+            Ok(Status::Locked(Some(Duration::from_secs(until - now))))
+        } else {
+            Ok(Status::Unlocked)
+        }
+    }
+
+    pub fn record_failure(&self) -> Result<(), String> {
+        if self.lockout.deny == 0 {
+            return Ok(());
+        }
+
+        let now = now()?;
+        let window = self.lockout.fail_interval.as_secs();
+        let mut failures: Vec<u64> = self.read()?.into_iter().filter(|t| now.saturating_sub(*t) <= window).collect();
+        failures.push(now);
+        self.write(&failures)
+    }
+
+    pub fn reset(&self) -> Result<(), String> {
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Could not remove faillock file: {}", e)),
+        }
+    }
+}
+
+fn now() -> Result<u64, String> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process, thread};
+
+    #[test]
+    fn failures_lock_until_reset() {
+        let mut lockout = Lockout::new();
+        lockout.statedir = format!("{}/wfp-lockout-{}", std::env::temp_dir().display(), process::id());
+
+        let tally = lockout.lock("bob").unwrap();
+        assert!(matches!(tally.status().unwrap(), Status::Unlocked));
+        for _ in 0..3 {
+            tally.record_failure().unwrap();
+        }
+        assert!(matches!(tally.status().unwrap(), Status::Locked(Some(_))));
+
+        // Waits for the tally of the login above to be dropped.
+        let other = lockout.clone();
+        let unlock = thread::spawn(move || other.reset("bob").unwrap());
+        thread::sleep(Duration::from_millis(100));
+        assert!(!unlock.is_finished());
+        drop(tally);
+        unlock.join().unwrap();
+        assert!(matches!(lockout.status("bob").unwrap(), Status::Unlocked));
+
+        // The lock is only held for the update itself.
+        lockout.record_failure("bob").unwrap();
+        let tally = lockout.lock("bob").unwrap();
+        assert_eq!(tally.read().unwrap().len(), 1);
+        drop(tally);
+
+        fs::remove_dir_all(&lockout.statedir).unwrap();
+    }
+}
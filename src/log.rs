@@ -0,0 +1,105 @@
+//! Diagnostics that must not end up on the terminal of the user.
+//!
+//! Inside the pam module messages go to syslog through `pam_syslog` while a hook is running and
+//! are dropped otherwise, as stdout and stderr belong to the application (sshd, gdm, ...).
+//! `wfp` writes them to stderr instead.
+//!
+//! The handle and settings are kept per thread, as applications may run hooks for several
+//! handles at once. Messages of other threads, such as workers, which may outlive the hook and
+//! its handle, never go to syslog.
+
+use std::{
+    cell::Cell,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use pam::{
+    module::PamHandle,
+    constants::{PamLogPriority, LOG_ERR, LOG_WARNING, LOG_INFO, LOG_DEBUG},
+};
+
+thread_local! {
+    static HANDLE: Cell<*const PamHandle> = const { Cell::new(ptr::null()) };
+    static DEBUG: Cell<bool> = const { Cell::new(false) };
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+#[derive(PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn priority(&self) -> PamLogPriority {
+        match *self {
+            Level::Error => LOG_ERR,
+            Level::Warning => LOG_WARNING,
+            Level::Info => LOG_INFO,
+            Level::Debug => LOG_DEBUG,
+        }
+    }
+}
+
+/// Logs through `pam_syslog` on the handle from this thread until the returned scope is dropped.
+pub fn attach(pamh: &PamHandle) -> Scope {
+    HANDLE.with(|h| h.set(pamh));
+    configure(false, false);
+    Scope
+}
+
+pub struct Scope;
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        HANDLE.with(|h| h.set(ptr::null()));
+        configure(false, false);
+    }
+}
+
+/// Logs to stderr when no pam handle is attached, for use in `wfp`.
+pub fn to_stderr() {
+    STDERR.store(true, Ordering::SeqCst);
+}
+
+/// Sets whether debug messages of this thread are logged and whether only warnings and errors are.
+pub fn configure(debug: bool, quiet: bool) {
+    DEBUG.with(|d| d.set(debug));
+    QUIET.with(|q| q.set(quiet));
+}
+
+pub fn log(level: Level, msg: &str) {
+    if level == Level::Debug && !DEBUG.with(|d| d.get()) {
+        return;
+    }
+    if level == Level::Info && QUIET.with(|q| q.get()) {
+        return;
+    }
+
+    let handle = HANDLE.with(|h| h.get());
+    if !handle.is_null() {
+        unsafe { &*handle }.syslog(level.priority(), msg);
+    } else if STDERR.load(Ordering::SeqCst) {
+        eprintln!("{}", msg);
+    }
+}
+
+pub fn error(msg: &str) {
+    log(Level::Error, msg)
+}
+
+pub fn warning(msg: &str) {
+    log(Level::Warning, msg)
+}
+
+pub fn info(msg: &str) {
+    log(Level::Info, msg)
+}
+
+pub fn debug(msg: &str) {
+    log(Level::Debug, msg)
+}
@@ -3,153 +3,251 @@ extern crate openssl;
 extern crate reqwest;
 #[macro_use]
 extern crate json;
+extern crate libc;
 extern crate base64;
 extern crate qrcode;
 extern crate eventsource;
+extern crate futures;
+extern crate tokio_core;
 
+use json::JsonValue;
 use qrcode::QrCode;
 
+mod audit;
+mod auth;
 mod config;
+mod credentials;
 mod crypto;
+mod doctor;
+mod firebase;
+mod gc;
+mod lockout;
+mod log;
+mod messages;
+mod remote;
+mod session;
 mod transport;
 mod worker;
 
+use auth::*;
 use config::*;
 use crypto::*;
+use doctor::doctor;
+use firebase::watch;
+use gc::gc;
+use lockout::DEFAULT_STATEDIR;
+use remote::listen;
 use transport::*;
-use worker::*;
 
 use std::{
+    collections::HashMap,
     env,
-    time::{Duration, Instant},
-    thread,
-    sync::mpsc,
+    process,
+    time::Duration,
+    sync::mpsc::{self, RecvTimeoutError},
 };
 
 fn main() {
+    log::to_stderr();
+
     match || -> Result<(), String> {
         let args: Vec<String> = env::args().collect();
-        if args.len() != 5 {
-            return Err(usage(&args[0][..]));
-        }
-
-        let baseurl = &args[1];
-        let keyfile = &args[2];
-        let username = &args[3];
-        let name = &args[4];
-        let timeout = Duration::from_secs(30);
-
-        let id = encode(&random(32)?);
-        let wrapping_key = SecretKey::from(&random(32)?)?;
-        let privkey = PrivKey::generate()?;
-
-        let code = QrCode::new(json::stringify(object!{
-            "id" => &id[..],
-            "wrappingKey" => encode(&wrapping_key.bytes),
-            "name" => &name[..],
-            "publicKey" => encode(&privkey.public_key.to_der()?),
-        })).map_err(|e| e.to_string())?;
-
-        let string = code.render::<char>()
-            .quiet_zone(false)
-            .module_dimensions(2, 1)
-            .build();
-        println!("{}", string);
-
-        let (response, receiver) = mpsc::channel();
-
-        let url = build_url(&baseurl, "i", &id, None)?;
-        let thread_response = response.clone();
-
-        let _worker = collect_response(url, timeout, true, move |event: String, data: String| -> Result<Option<Vec<u8>>, String> {
-            match &event[..] {
-                "put" => {
-                    let d = json::parse(&data).map_err(|e| e.to_string())?;
-                    if let Some(path) = d["path"].as_str() {
-                        let data = &d["data"];
-
-                        let check_response = |public_key: &str, signature: &str| -> Result<Option<Vec<u8>>, String> {
-                            let pubkey = decode(public_key)?;
-                            let sign = decode(&signature)?;
-                            if wrapping_key.verify(&pubkey, &sign) {
-                                return Ok(Some(pubkey));
-                            } else {
-                                return Err(String::from("Bad signature"));
-                            }
-                        };
-
-                        if path == "/" {
-                            if let Some(public_key) = data["p"].as_str() {
-                                if let Some(signature) = data["s"].as_str() {
-                                    return check_response(public_key, signature);
-                                }
-                            }
-
-                        }
-                    }
+        match args.get(1).map(|a| &a[..]) {
+            Some("unlock") => return unlock(&args),
+            Some("test") => return test(&args),
+            Some("doctor") => return doctor(&args[0], &args[2..]),
+            Some("listen") if args.len() > 2 && !args[2].starts_with("--") => {
+                if args.len() != 4 && args.len() != 5 {
+                    return Err(usage(&args[0][..]));
                 }
-                "patch" => {
-                    // Should never happen as we don't modify data
-                    return Err(format!("Patch received, but we normally don't modify data..."));
-                }
-                "keep-alive" => (),
-                "cancel" => {
-                    return Err(format!("Cancel received, exiting due to modified permissions..."));
-                }
-                "auth_revoked" => (), // Ignore as we're not using auth
-                _ => (), // Ignore the else case as well
+                return listen(&Backend::default(), &args[2], &args[3], args.get(4).map(|s| &s[..]).unwrap_or(DEFAULT_STATEDIR), None);
+            }
+            Some("listen") => {
+                let options = load_options(&args[0], &args[2..])?;
+                return listen(&Backend::new(&options)?, &options.baseurl, &options.keyfile, &options.statedir, options.audit.as_ref());
             }
-            Ok(None)
-        }, move |res: Result<Vec<u8>, String>| {
-            match thread_response.send(res) {
-                Ok(_) => (),
-                Err(_) => (),
+            Some("gc") => {
+                let options = load_options(&args[0], &args[2..])?;
+                return gc(&options);
             }
-        });
-
-        let (timeout_sender, timeout_receiver) = mpsc::channel();
-        let timeout_worker = Worker {
-            thread: thread::spawn(move || {
-                let elapsed = Instant::now();
-                loop {
-                    if let Ok(_) = timeout_receiver.try_recv() {
-                        return;
-                    }
-                    if elapsed.elapsed() > timeout {
-                        break;
-                    }
-                    thread::sleep(Duration::from_millis(100));
+            Some("pair") => {
+                if args.len() < 4 {
+                    return Err(usage(&args[0][..]));
                 }
-                match response.send(Err(String::from("Timeout"))) {
-                    Ok(_) => (),
-                    Err(_) => (),
-                };
-            }),
-            sender: timeout_sender,
-            cleanup: None,
-        };
-
-        println!("Waiting for qr code scan...");
-        let public_key = receiver.recv().map_err(|e| e.to_string())??;
-
-        let device = Device {
-            id,
-            other_key: PubKey::from_der(&public_key)?,
-            own_key: privkey,
-            username: username.clone(),
-        };
-
-        device.write(keyfile)?;
-        println!("Successfully set up device!");
-        Ok(())
+                let options = load_options(&args[0], &args[4..])?;
+                return pair(&Backend::new(&options)?, &options.baseurl, &options.keyfile, &args[2], &args[3]);
+            }
+            _ => (),
+        }
+
+        if args.len() != 5 {
+            return Err(usage(&args[0][..]));
+        }
+        pair(&Backend::default(), &args[1], &args[2], &args[3], &args[4])
     }() {
         Ok(_) => (),
         Err(e) => {
             println!("{}", &e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Loads the settings of `/etc/wfp.conf`, overridden by `--<setting> <value>` flags like the module
+/// arguments override it, and checks that a backend is configured.
+fn load_options(program: &str, flags: &[String]) -> Result<Options, String> {
+    let mut args = Vec::new();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        if !flag.starts_with("--") {
+            return Err(usage(program));
+        }
+        let value = flags.next().ok_or_else(|| usage(program))?;
+        args.push(format!("{}={}", &flag[2..], value));
+    }
+
+    let options = Options::load(&args.iter().map(|a| &a[..]).collect::<Vec<&str>>())?;
+    if options.baseurl.is_empty() || options.keyfile.is_empty() {
+        return Err(format!("No baseurl or keyfile configured, set them in {} or pass --baseurl and --keyfile", DEFAULT_CONFIG));
+    }
+    Ok(options)
+}
+
+/// Pairs a device by showing a QR code for the app to scan, and adds it to the keyfile.
+fn pair(backend: &Backend, baseurl: &str, keyfile: &str, username: &str, name: &str) -> Result<(), String> {
+    let timeout = Duration::from_secs(30);
+
+    let id = encode(&random(32)?);
+    let wrapping_key = SecretKey::from(&random(32)?)?;
+    let privkey = PrivKey::generate()?;
+
+    let code = QrCode::new(json::stringify(object!{
+        "id" => &id[..],
+        "wrappingKey" => encode(&wrapping_key.bytes),
+        "name" => &name[..],
+        "publicKey" => encode(&privkey.public_key.to_der()?),
+    })).map_err(|e| e.to_string())?;
+
+    let string = code.render::<char>()
+        .quiet_zone(false)
+        .module_dimensions(2, 1)
+        .build();
+    println!("{}", string);
+
+    let (response, receiver) = mpsc::channel();
+
+    let url = build_url(&baseurl, "i", &id, None)?;
+
+    let _worker = collect_response(backend, url.clone(), vec![url], watch("/", move |data: &JsonValue| -> Result<Option<Vec<u8>>, String> {
+        if let (Some(public_key), Some(signature)) = (data["p"].as_str(), data["s"].as_str()) {
+            let pubkey = decode(public_key)?;
+            let sign = decode(signature)?;
+            if wrapping_key.verify(&pubkey, &sign) {
+                return Ok(Some(pubkey));
+            } else {
+                return Err(String::from("Bad signature"));
+            }
+        }
+        Ok(None)
+    }), move |res: Result<Vec<u8>, String>| {
+        match response.send(res) {
+            Ok(_) => (),
+            Err(_) => (),
+        }
+    })?;
+
+    println!("Waiting for qr code scan...");
+    let public_key = match receiver.recv_timeout(timeout) {
+        Ok(result) => result?,
+        Err(RecvTimeoutError::Timeout) => return Err(String::from("Timeout")),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let device = Device {
+        id,
+        name: Some(String::from(name)),
+        other_key: PubKey::from_der(&public_key)?,
+        own_key: privkey,
+        username: String::from(username),
+    };
+
+    device.write(keyfile)?;
+    println!("Successfully set up device!");
+    Ok(())
+}
+
+fn unlock(args: &[String]) -> Result<(), String> {
+    if args.len() != 3 && args.len() != 4 {
+        return Err(usage(&args[0][..]));
+    }
+
+    let username = &args[2];
+    let mut lockout = Options::load(&[])?.lockout;
+    if let Some(statedir) = args.get(3) {
+        lockout.statedir = statedir.clone();
+    }
+
+    lockout.reset(username)?;
+    println!("Successfully unlocked {}!", username);
+    Ok(())
+}
+
+/// Sends challenges to the devices of a user like `sm_authenticate` does, without logging in or
+/// touching the lockout state.
+fn test(args: &[String]) -> Result<(), String> {
+    let mut username = None;
+    let mut flags = Vec::new();
+    let mut rest = args[2..].iter();
+    while let Some(flag) = rest.next() {
+        if flag == "--user" {
+            username = Some(rest.next().ok_or_else(|| usage(&args[0][..]))?.clone());
+        } else {
+            flags.push(flag.clone());
+        }
+    }
+    let username = username.ok_or_else(|| usage(&args[0][..]))?;
+    let options = load_options(&args[0], &flags)?;
+    let backend = Backend::new(&options)?;
+    let (keyfile, baseurl, timeout) = (options.keyfile, options.baseurl, options.timeout);
+
+    let devices = Device::fetch_all(&keyfile, &username);
+    if devices.is_empty() {
+        return Err(format!("No devices paired for {} in {}", username, keyfile));
+    }
+
+    let mut names = HashMap::new();
+    let challenges = Challenges::send(&backend, &baseurl, devices, timeout, |device| {
+        let name = device.name.clone().unwrap_or_else(|| String::from("unnamed device"));
+        println!("Sending challenge to {} ({})...", name, device.id);
+        names.insert(device.id.clone(), name);
+    })?;
+    println!("Waiting up to {} seconds for an answer...", timeout.as_secs());
+
+    let decision = challenges.wait()?;
+    let name = decision.device_id().and_then(|id| names.get(id)).cloned().unwrap_or_default();
+    println!("Result: {}", decision.as_str());
+    match decision {
+        Decision::Approved(approval) => {
+            println!("Approved by {} ({}) for challenge {}", name, approval.device_id, approval.challenge);
+            Ok(())
         }
+        Decision::Denied(id) => Err(format!("Denied by {} ({})", name, id)),
+        Decision::BadSignature(id) => Err(format!("Bad signature from {} ({})", name, id)),
+        Decision::Timeout => Err(format!("No answer within {} seconds", timeout.as_secs())),
     }
 }
 
 fn usage(program: &str) -> String {
-    format!("Usage: {} <baseurl> <keyfile> <username> <device-name>", program)
+    let lines = [
+        "<baseurl> <keyfile> <username> <device-name>",
+        "pair <username> <device-name> [--<setting> <value>...]",
+        "unlock <username> [statedir]",
+        "listen <baseurl> <keyfile> [statedir]",
+        "listen [--<setting> <value>...]",
+        "test --user <username> [--<setting> <value>...]",
+        "gc [--<setting> <value>...]",
+        "doctor [--keyfile <keyfile>] [--baseurl <baseurl>] [--pamdir <dir>] [--statedir <dir>]",
+    ];
+    let usage: Vec<String> = lines.iter().map(|l| format!("{} {}", program, l)).collect();
+    format!("Usage: {}\n\nSettings, such as baseurl, keyfile, timeout or backend, default to {}.", usage.join("\n       "), DEFAULT_CONFIG)
 }
\ No newline at end of file
@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use log;
+
+/// The messages shown to the user and their default templates.
+///
+/// Templates may contain the placeholders `{device_name}`, `{host}`, `{timeout}` and, for the
+/// lockout messages, `{remaining}`.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("prompt", "Approve login on {device_name}..."),
+    ("denied_msg", "Login denied on {device_name}"),
+    ("timeout_msg", "No approval within {timeout} seconds"),
+    ("locked_msg", "Too many failed approvals, try again in {remaining} seconds"),
+    ("unlock_msg", "Too many failed approvals, ask an administrator to unlock your account"),
+    ("error_msg", "Login failed, ask an administrator to check the system log"),
+    ("no_password_msg", "No password given"),
+];
+
+#[derive(Clone)]
+pub struct Messages {
+    templates: HashMap<String, String>,
+}
+
+impl Messages {
+    pub fn new() -> Messages {
+        Messages {
+            templates: HashMap::new(),
+        }
+    }
+
+    pub fn is_key(key: &str) -> bool {
+        DEFAULTS.iter().any(|&(k, _)| k == key)
+    }
+
+    pub fn set(&mut self, key: &str, template: &str) {
+        self.templates.insert(String::from(key), String::from(template));
+    }
+
+    /// Fills in the templates that weren't set explicitly from the catalog in `dir` that best
+    /// matches `locale`, such as `de_DE.UTF-8`, then `de_DE` and `de`.
+    ///
+    /// Catalogs contain one `key=template` per line, lines starting with `#` are ignored. The
+    /// locale may come from the user, so anything but a plain locale name is ignored, and the
+    /// catalog is never logged.
+    pub fn load_catalog(&mut self, dir: &str, locale: &str) {
+        if !is_locale(locale) {
+            log::warning("Ignoring invalid locale");
+            return;
+        }
+        let locale = locale.split('@').next().unwrap_or(locale);
+        let language = locale.split('.').next().unwrap_or(locale);
+        let candidates = [locale, language, language.split('_').next().unwrap_or(language)];
+
+        let file = match candidates.iter().filter_map(|c| File::open(Path::new(dir).join(c)).ok()).next() {
+            Some(f) => f,
+            None => {
+                log::debug(&format!("No message catalog for locale {} in {}", locale, dir));
+                return;
+            }
+        };
+
+        for (number, l) in BufReader::new(file).lines().enumerate() {
+            let line = match l {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warning(&format!("Could not read message catalog: {}", e));
+                    return;
+                }
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(pos) if Messages::is_key(line[..pos].trim()) => {
+                    self.templates.entry(String::from(line[..pos].trim())).or_insert_with(|| String::from(&line[pos + 1..]));
+                }
+                _ => log::warning(&format!("Invalid message catalog line {}", number + 1)),
+            }
+        }
+    }
+
+    /// Renders the template for `key`, replacing `{name}` with the value for each pair in `vars`.
+    pub fn render(&self, key: &str, vars: &[(&str, &str)]) -> String {
+        let template = match self.templates.get(key) {
+            Some(t) => &t[..],
+            None => DEFAULTS.iter().find(|&&(k, _)| k == key).map(|&(_, t)| t).unwrap_or(key),
+        };
+        vars.iter().fold(String::from(template), |msg, &(name, value)| msg.replace(&format!("{{{}}}", name), value))
+    }
+}
+
+/// Whether `locale` has the form `language[_TERRITORY][.codeset][@modifier]`.
+fn is_locale(locale: &str) -> bool {
+    let mut rest = locale.splitn(2, '@');
+    let locale = rest.next().unwrap_or("");
+    if let Some(modifier) = rest.next() {
+        if modifier.is_empty() || !modifier.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return false;
+        }
+    }
+
+    let mut rest = locale.splitn(2, '.');
+    let language = rest.next().unwrap_or("");
+    if let Some(codeset) = rest.next() {
+        if codeset.is_empty() || !codeset.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return false;
+        }
+    }
+
+    let mut rest = language.splitn(2, '_');
+    let language = rest.next().unwrap_or("");
+    if language.len() < 2 || language.len() > 3 || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return false;
+    }
+    match rest.next() {
+        Some(territory) => territory.len() == 2 && territory.chars().all(|c| c.is_ascii_uppercase()),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locales() {
+        for locale in &["de", "de_DE", "de_DE.UTF-8", "sr_RS@latin", "ber_MA.utf8@tifinagh", "fil"] {
+            assert!(is_locale(locale), "{}", locale);
+        }
+        for locale in &["", "C", "POSIX", "/etc/shadow", "../../../etc/shadow", "de/../x", "de_de", "de_DE.", "de_DE@", "de_DE.UTF-8/x", "deutsch"] {
+            assert!(!is_locale(locale), "{}", locale);
+        }
+    }
+}
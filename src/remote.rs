@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use eventsource::reconnect::ReconnectPolicy;
+use eventsource::reqwest::ClientBuilder as EventSourceBuilder;
+
+use json::{self, JsonValue};
+
+use audit::{self, Sink};
+use config::Device;
+use firebase::{StreamError, Tree};
+use log;
+use session::*;
+use transport::*;
+
+/// How far the issue time of a command may be off before it is rejected as a replay.
+const MAX_COMMAND_AGE: u64 = 5 * 60 * 1000;
+
+/// Listens on `h/<host>` for commands signed by a paired device and executes them.
+///
+/// Commands are `{"r": <record>, "s": <signature>}` nodes like the session records, where the
+/// record is `{"command": "terminate", "session": <id>, "device": <id>, "issued": <ms>}`. Each
+/// command is audited to `sink`, whether it was executed or rejected.
+pub fn listen(backend: &Backend, baseurl: &str, keyfile: &str, statedir: &str, sink: Option<&Sink>) -> Result<(), String> {
+    let url = build_url(baseurl, "h", &host_id(), None)?;
+    // When commands were seen, so that they are executed once. A command may be issued up to
+    // `MAX_COMMAND_AGE` in the future, so it is only expired twice that long after it was seen.
+    let mut seen: HashMap<String, Instant> = HashMap::new();
+    let forget_after = Duration::from_millis(2 * MAX_COMMAND_AGE);
+    let mut tree = Tree::new();
+
+    log::info(&format!("Listening for commands on {}", url));
+    // Reconnects with a new token after auth_revoked. Otherwise the client reconnects by itself,
+    // without giving up, as the listener runs for as long as the host is up.
+    'connect: loop {
+        let mut authorized = url.clone();
+        let headers = backend.authorize(&mut authorized)?;
+        let client = EventSourceBuilder::new(authorized)
+            .headers(headers)
+            .reconnect(ReconnectPolicy::unlimited())
+            .build()
+            .map_err(|e| describe_stream(&e))?;
+        for result in client {
+            let event = match result {
+                Ok(e) => e,
+                Err(e) => {
+                    log::warning(&format!("Stream error: {}", describe_stream(&e)));
+                    continue;
+                }
+            };
+
+            let changes = match tree.apply(event.event_type.as_ref().map_or("", |e| &e[..]), &event.data) {
+                Ok(changes) => changes,
+                Err(StreamError::Invalid(e)) => {
+                    log::warning(&format!("Invalid event data: {}", e));
+                    continue;
+                }
+                Err(e) => match (e, &backend.credentials) {
+                    (StreamError::AuthRevoked, &Some(ref credentials)) => {
+                        log::info("Token revoked, reconnecting");
+                        credentials.invalidate();
+                        continue 'connect;
+                    }
+                    (e, _) => return Err(e.into()),
+                },
+            };
+            let commands = changes.iter().flat_map(|change| commands(&change.path, &change.value));
+
+            seen.retain(|_, &mut at| at.elapsed() < forget_after);
+            for (id, command) in commands {
+                if seen.insert(id.clone(), Instant::now()).is_some() {
+                    continue;
+                }
+
+                let mut record = audit::Command::new(&id);
+                match execute(keyfile, statedir, &command, &mut record) {
+                    Ok((session, device)) => log::info(&format!("Terminated session {} of {} (pid {}) on request of device {}", session.id, session.username, session.pid, device.id)),
+                    Err(e) => {
+                        log::warning(&format!("Rejected command {}: {}", id, e));
+                        record.error = Some(e);
+                    }
+                }
+                if let Some(sink) = sink {
+                    record.write(sink);
+                }
+
+                // Commands are one-shot, so remove them whether they were executed or not.
+                if let Err(e) = build_url(baseurl, "h", &host_id(), Some(&id)).and_then(|u| remove(backend, u)) {
+                    log::warning(&format!("Could not remove command {}: {}", id, e));
+                }
+            }
+        }
+        return Err(String::from("Connection closed"));
+    }
+}
+
+/// Extracts the `(id, command)` pairs that a change at `path` sets.
+fn commands(path: &str, data: &JsonValue) -> Vec<(String, JsonValue)> {
+    let path = path.trim_left_matches('/');
+    if path.is_empty() {
+        data.entries().filter(|&(_, v)| !v.is_null()).map(|(k, v)| (String::from(k), v.clone())).collect()
+    } else if path.contains('/') || data.is_null() {
+        // Only whole commands are of interest
+        Vec::new()
+    } else {
+        vec![(String::from(path), data.clone())]
+    }
+}
+
+/// Verifies and executes a command, filling in `record` as far as it gets.
+fn execute(keyfile: &str, statedir: &str, command: &JsonValue, record: &mut audit::Command) -> Result<(Registration, Device), String> {
+    let missing = |field: &str| format!("Command without {}", field);
+
+    let record = command["r"].as_str().ok_or_else(|| missing("record"))?;
+    let signature = decode(command["s"].as_str().ok_or_else(|| missing("signature"))?)?;
+    let data = json::parse(record).map_err(|e| e.to_string())?;
+
+    record.command = data["command"].as_str().map(String::from);
+    record.session = data["session"].as_str().map(String::from);
+    record.device = data["device"].as_str().map(String::from);
+    match data["command"].as_str() {
+        Some("terminate") => (),
+        _ => return Err(format!("Unknown command: {}", data["command"])),
+    }
+
+    let session = Registration::load(statedir, data["session"].as_str().ok_or_else(|| missing("session"))?)?;
+    record.username = Some(session.username.clone());
+    // Any device paired for the user of the session may terminate it.
+    let device = Device::find(keyfile, &session.username, data["device"].as_str().ok_or_else(|| missing("device"))?)?;
+    if !device.other_key.verify(record.as_bytes(), &signature) {
+        return Err(String::from("Bad signature"));
+    }
+
+    let issued = data["issued"].as_u64().ok_or_else(|| missing("issue time"))?;
+    let now = timestamp()?;
+    if (if now > issued { now - issued } else { issued - now }) > MAX_COMMAND_AGE {
+        return Err(String::from("Command expired"));
+    }
+
+    session.terminate()?;
+    Ok((session, device))
+}
@@ -0,0 +1,184 @@
+use std::{
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use json::{self, JsonValue};
+use libc;
+
+use config::Device;
+use transport::*;
+
+/// Where `loginctl` is installed, looked up by absolute path as `wfp listen` runs as root.
+const LOGINCTL: &[&str] = &["/usr/bin/loginctl", "/bin/loginctl"];
+/// The state files of logind sessions.
+const LOGIND_SESSIONS: &str = "/run/systemd/sessions";
+
+/// Key under which the open session is stored with `set_data`.
+pub const SESSION_DATA: &str = "wfp_session";
+
+/// A login session approved by one of the devices, published to `s/<device>/<session>`.
+pub struct Session {
+    pub id: String,
+    pub device_id: String,
+    /// The challenge whose response approved this session.
+    pub challenge: String,
+    pub username: String,
+    pub host: String,
+    pub service: Option<String>,
+    pub tty: Option<String>,
+    pub rhost: Option<String>,
+    pub pid: u32,
+    /// The logind session id, if `pam_systemd` ran before us.
+    pub logind: Option<String>,
+    pub started: u64,
+}
+
+impl Session {
+    fn record(&self, ended: Option<u64>) -> JsonValue {
+        object!{
+            "id" => &self.id[..],
+            "user" => &self.username[..],
+            "host" => &self.host[..],
+            "hostId" => host_id(),
+            "service" => self.service.clone(),
+            "tty" => self.tty.clone(),
+            "rhost" => self.rhost.clone(),
+            "pid" => self.pid,
+            "challenge" => &self.challenge[..],
+            "started" => self.started,
+            "ended" => ended,
+        }
+    }
+
+    /// Publishes the session as started, or as ended if `ended` is given, signed by our key
+    /// for `device`.
+    pub fn publish(&self, backend: &Backend, baseurl: &str, device: &Device, ended: Option<u64>) -> Result<(), String> {
+        let record = json::stringify(self.record(ended));
+        let signature = device.own_key.sign(record.as_bytes())?;
+        let url = build_url(baseurl, "s", &device.id, Some(&self.id))?;
+        send_record(backend, url, &record, &signature)
+    }
+
+    /// Remembers the session locally, so `wfp listen` can terminate it on request of the phone.
+    pub fn register(&self, statedir: &str) -> Result<(), String> {
+        let path = registration_path(statedir, &self.id)?;
+        if let Some(dir) = path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir).map_err(|e| format!("Could not create state directory: {}", e))?;
+        }
+
+        let mut file = OpenOptions::new().write(true).truncate(true).create(true).mode(0o600).open(&path).map_err(|e| format!("Could not open session file: {}", e))?;
+        write!(file, "{}", json::stringify(object!{
+            "user" => &self.username[..],
+            "device" => &self.device_id[..],
+            "pid" => self.pid,
+            "startTime" => process_start_time(self.pid),
+            "logind" => self.logind.clone(),
+        })).map_err(|e| format!("Could not write session file: {}", e))
+    }
+
+    pub fn unregister(&self, statedir: &str) -> Result<(), String> {
+        match fs::remove_file(registration_path(statedir, &self.id)?) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Could not remove session file: {}", e)),
+        }
+    }
+}
+
+/// A session registered on this host by `Session::register`.
+pub struct Registration {
+    pub id: String,
+    pub username: String,
+    pub device_id: String,
+    pub pid: u32,
+    pub start_time: Option<u64>,
+    pub logind: Option<String>,
+}
+
+impl Registration {
+    pub fn load(statedir: &str, id: &str) -> Result<Registration, String> {
+        let mut contents = String::new();
+        File::open(registration_path(statedir, id)?).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| format!("Unknown session {}: {}", id, e))?;
+        let data = json::parse(&contents).map_err(|e| e.to_string())?;
+
+        let invalid = || format!("Invalid session file: {}", id);
+        Ok(Registration {
+            id: String::from(id),
+            username: String::from(data["user"].as_str().ok_or_else(&invalid)?),
+            device_id: String::from(data["device"].as_str().ok_or_else(&invalid)?),
+            pid: data["pid"].as_u32().ok_or_else(&invalid)?,
+            start_time: data["startTime"].as_u64(),
+            logind: data["logind"].as_str().map(String::from),
+        })
+    }
+
+    /// Terminates the session through logind if possible, by sending `SIGTERM` to the process
+    /// that opened it otherwise. Either way, it first makes sure that the session wasn't replaced
+    /// by another one after it ended without being closed.
+    pub fn terminate(&self) -> Result<(), String> {
+        if let Some(ref logind) = self.logind {
+            // Session ids are reused after a reboot.
+            if logind_user(logind).as_ref() != Some(&self.username) {
+                return Err(format!("Logind session {} of session {} is gone", logind, self.id));
+            }
+            let loginctl = LOGINCTL.iter().find(|p| Path::new(p).is_file()).ok_or_else(|| String::from("loginctl not found"))?;
+            let status = Command::new(loginctl).arg("terminate-session").arg(logind).status().map_err(|e| e.to_string())?;
+            if !status.success() {
+                return Err(format!("Could not terminate session {}: {}", self.id, status));
+            }
+            return Ok(());
+        }
+
+        // Make sure the pid wasn't reused after the session process died without closing it.
+        if self.start_time.is_none() || process_start_time(self.pid) != self.start_time {
+            return Err(format!("Process {} of session {} is gone", self.pid, self.id));
+        }
+        if unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGTERM) } != 0 {
+            return Err(format!("Could not terminate session {}: {}", self.id, io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+fn registration_path(statedir: &str, id: &str) -> Result<PathBuf, String> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Invalid session id: {}", id));
+    }
+    Ok(Path::new(statedir).join("sessions").join(id))
+}
+
+/// The user of a logind session, from its state file, see `sd_session_get_username(3)`.
+fn logind_user(id: &str) -> Option<String> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let mut state = String::new();
+    File::open(Path::new(LOGIND_SESSIONS).join(id)).and_then(|mut f| f.read_to_string(&mut state)).ok()?;
+    state.lines().find(|l| l.starts_with("USER=")).map(|l| String::from(&l[5..]))
+}
+
+/// The start time of a process in clock ticks since boot, see `proc(5)`.
+fn process_start_time(pid: u32) -> Option<u64> {
+    let mut stat = String::new();
+    File::open(format!("/proc/{}/stat", pid)).and_then(|mut f| f.read_to_string(&mut stat)).ok()?;
+    // The command name may contain spaces, so start counting after its closing parenthesis.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    fields.get(19)?.parse().ok()
+}
+
+pub fn hostname() -> String {
+    let mut name = String::new();
+    match File::open("/proc/sys/kernel/hostname").and_then(|mut f| f.read_to_string(&mut name)) {
+        Ok(_) => String::from(name.trim()),
+        Err(_) => String::from("localhost"),
+    }
+}
+
+/// The id of the channel `h/<host>` on which `wfp listen` receives commands for this host.
+pub fn host_id() -> String {
+    encode(hostname().as_bytes())
+}
@@ -1,19 +1,32 @@
 use reqwest::{
-    Url, Method, Client as ReqwestClient,
+    Url, Method, Client as ReqwestClient, ClientBuilder as ReqwestClientBuilder, Error as ReqwestError,
+    header::Headers,
 };
 
-use eventsource::reqwest::Client as EventSourceClient;
+use eventsource::reqwest::{
+    ClientBuilder as EventSourceBuilder,
+    Error as EventSourceError,
+    ErrorKind as EventSourceErrorKind,
+};
+
+use futures::{
+    Future, Stream,
+    future::Either,
+};
+use tokio_core::reactor::Core;
 
 use std::{
-    thread,
-    sync::mpsc,
-    time::Duration,
+    time::{Instant, SystemTime, UNIX_EPOCH},
     marker::Send,
+    sync::Arc,
 };
 
-use worker::Worker;
+use config::Options;
+use credentials::{self, Credentials};
+use log;
+use worker::{Worker, STOP_DEADLINE};
 
-use json::stringify;
+use json::{self, stringify, JsonValue};
 
 use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
 
@@ -25,6 +38,12 @@ pub fn encode(bytes: &[u8]) -> String {
     encode_config(bytes, URL_SAFE_NO_PAD)
 }
 
+/// Milliseconds since the epoch, as used for timestamps on the backend.
+pub fn timestamp() -> Result<u64, String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+    Ok(now.as_secs()*1000 + now.subsec_nanos() as u64 / 1000000)
+}
+
 pub fn build_url(base_url: &str, category: &str, id: &str, message: Option<&str>) -> Result<Url, String> {
     let url = {
         if let Some(msg) = message {
@@ -41,79 +60,228 @@ pub struct Info {
     pub signature: String,
 }
 
-pub struct PendingCleanup {
+/// The backend as used by a single login or command: the http client, and the credentials its
+/// requests are authenticated with, if any.
+#[derive(Clone)]
+pub struct Backend {
     pub client: ReqwestClient,
-    pub url: Url,
+    pub credentials: Option<Arc<Credentials>>,
+}
+
+impl Default for Backend {
+    /// A backend that is accessed anonymously.
+    fn default() -> Backend {
+        Backend {
+            client: ReqwestClient::new(),
+            credentials: None,
+        }
+    }
+}
+
+impl Backend {
+    /// Authenticates with the credentials of `options`, if it has any.
+    pub fn new(options: &Options) -> Result<Backend, String> {
+        Ok(Backend {
+            client: ReqwestClient::new(),
+            credentials: credentials::shared(options)?,
+        })
+    }
+
+    /// Adds the token of the credentials to `url` or to the returned headers, if there are any.
+    pub fn authorize(&self, url: &mut Url) -> Result<Headers, String> {
+        self.authorize_until(url, None)
+    }
+
+    /// Like `authorize`, but fails if signing in doesn't finish by `deadline`.
+    pub fn authorize_until(&self, url: &mut Url, deadline: Option<Instant>) -> Result<Headers, String> {
+        let mut headers = Headers::new();
+        if let Some(ref credentials) = self.credentials {
+            credentials.authorize(url, &mut headers, deadline)?;
+        }
+        Ok(headers)
+    }
+}
+
+/// The message of a failed request, without the query of its url, which may carry a token.
+pub fn describe(e: &ReqwestError) -> String {
+    let message = e.to_string();
+    match e.url() {
+        Some(url) => {
+            let mut redacted = url.clone();
+            redacted.set_query(None);
+            message.replace(url.as_str(), redacted.as_str())
+        }
+        None => message,
+    }
+}
+
+/// Like `describe`, for the errors of event streams.
+pub fn describe_stream(e: &EventSourceError) -> String {
+    match *e.kind() {
+        EventSourceErrorKind::Reqwest(ref e) => describe(e),
+        _ => e.to_string(),
+    }
+}
+
+pub struct PendingCleanup {
+    pub backend: Backend,
+    pub urls: Vec<Url>,
 }
 
 impl PendingCleanup {
-    pub fn cleanup(self) -> Result<(), String> {
-        cleanup_challenge(self.client, self.url)
+    /// Removes the urls in order, and stops at the first one that fails, so that an expiry entry
+    /// listed last outlives what it refers to and `wfp gc` can retry. Requests time out at
+    /// `deadline`, and none are made after it.
+    pub fn cleanup(self, deadline: Instant) -> Result<(), String> {
+        for url in self.urls {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(String::from("Out of time"));
+            }
+            let backend = Backend {
+                client: ReqwestClientBuilder::new().timeout(deadline - now).build().map_err(|e| describe(&e))?,
+                credentials: self.backend.credentials.clone(),
+            };
+            remove(&backend, url)?;
+        }
+        Ok(())
     }
 }
 
-fn send(client: &ReqwestClient, method: Method, url: Url, body: Option<String>) -> Result<(), String> {
-    let mut req = client.request(method, url);
+fn send(backend: &Backend, method: Method, mut url: Url, body: Option<String>) -> Result<(), String> {
+    let headers = backend.authorize(&mut url)?;
+    let mut req = backend.client.request(method, url);
+    req.headers(headers);
     req.query(&[("print", "silent")]);
     if let Some(b) = body {
         req.body(b);
     }
-    let res = req.send().map_err(|e| e.to_string())?;
+    let res = req.send().map_err(|e| describe(&e))?;
     if !res.status().is_success() {
         return Err(format!("Error: {:?}", res.status()));
     }
     Ok(())
 }
 
-pub fn send_challenge(client: &ReqwestClient, url: Url, signature: &[u8]) -> Result<(), String> {
-    send(client, Method::Put, url, Some(stringify(encode(signature))))
+pub fn send_challenge(backend: &Backend, url: Url, signature: &[u8]) -> Result<(), String> {
+    send(backend, Method::Put, url, Some(stringify(encode(signature))))
+}
+
+/// Publishes a record as `{"r": <record>, "s": <signature>}` so the phone can verify it.
+pub fn send_record(backend: &Backend, url: Url, record: &str, signature: &[u8]) -> Result<(), String> {
+    send(backend, Method::Put, url, Some(stringify(object!{
+        "r" => record,
+        "s" => encode(signature),
+    })))
+}
+
+/// Records when a challenge expires, in milliseconds since the epoch, so that it can be purged
+/// if the host never removes it.
+pub fn send_expiry(backend: &Backend, url: Url, expiry: u64) -> Result<(), String> {
+    send(backend, Method::Put, url, Some(expiry.to_string()))
 }
 
-pub fn cleanup_challenge(client: ReqwestClient, url: Url) -> Result<(), String> {
-    send(&client, Method::Delete, url, None)
+pub fn remove(backend: &Backend, url: Url) -> Result<(), String> {
+    send(backend, Method::Delete, url, None)
 }
 
-pub fn collect_response<DC, RC, R>(url: Url, timeout: Duration, do_cleanup: bool, data_callback: DC, response_callback: RC) -> Worker where
+/// Reads the value at `url`, `Null` if there is none.
+pub fn fetch(backend: &Backend, mut url: Url) -> Result<JsonValue, String> {
+    let headers = backend.authorize(&mut url)?;
+    let mut res = backend.client.get(url).headers(headers).send().map_err(|e| describe(&e))?;
+    if !res.status().is_success() {
+        return Err(format!("Error: {:?}", res.status()));
+    }
+    let body = res.text().map_err(|e| describe(&e))?;
+    json::parse(&body).map_err(|e| e.to_string())
+}
+
+/// How often a stream is reopened after the backend revoked its token, before it fails.
+const MAX_RECONNECTS: usize = 3;
+
+/// Listens on `url` until `data_callback` decides, and passes the result to `response_callback`,
+/// also if the stream fails. The caller waits for it with a timeout, and stopping the worker
+/// closes the connection and removes the `cleanup` urls.
+///
+/// The first token is fetched before the worker starts, so that credential errors are returned
+/// right away. If the backend revokes the token, the worker fetches a new one within
+/// `STOP_DEADLINE` and reconnects, up to `MAX_RECONNECTS` times.
+pub fn collect_response<DC, RC, R>(backend: &Backend, url: Url, cleanup: Vec<Url>, data_callback: DC, response_callback: RC) -> Result<Worker, String> where
     DC: Fn(String, String) -> Result<Option<R>, String> + Send + 'static,
     RC: Fn(Result<R, String>) + Send + 'static {
 
-    let (timeout_sender, timeout_receiver) = mpsc::channel();
-    let thread_url = url.clone();
-
-    let cleanup = if do_cleanup {
+    let cleanup = if cleanup.is_empty() {
+        None
+    } else {
         Some(PendingCleanup {
-            client: ReqwestClient::new(),
-            url,
+            backend: backend.clone(),
+            urls: cleanup,
         })
-    }  else {
-        None
     };
 
-    Worker {
-        thread: thread::spawn(move || {
-            let client = EventSourceClient::new(thread_url, Some(timeout));
-            for result in client {
-                if let Ok(_) = timeout_receiver.try_recv() {
-                    return response_callback(Err(String::from("Timeout")));
-                }
-
-                let event = match result.map_err(|e| e.to_string()) {
-                    Ok(e) => e,
-                    Err(e) => return response_callback(Err(e)),
-                };
-                if let Some(evt) = event.event_type {
-                    let data = event.data;
+    // The first connection is authorized before spawning, so that credential errors are returned.
+    let mut authorized = url.clone();
+    let headers = backend.authorize(&mut authorized)?;
+    let backend = backend.clone();
+    Ok(Worker::spawn(cleanup, move |cancelled| {
+        let mut core = match Core::new() {
+            Ok(c) => c,
+            Err(e) => return response_callback(Err(e.to_string())),
+        };
 
-                    match data_callback(evt, data) {
-                        Ok(Some(r)) => return response_callback(Ok(r)),
-                        Ok(None) => (),
+        let mut cancelled = cancelled;
+        let mut connection = Some((authorized, headers));
+        let mut reconnects = 0;
+        loop {
+            let (authorized, headers) = match connection.take() {
+                Some(c) => c,
+                None => {
+                    // Bounded, as the worker can't be cancelled meanwhile and has to stop in time.
+                    let mut authorized = url.clone();
+                    match backend.authorize_until(&mut authorized, Some(Instant::now() + STOP_DEADLINE)) {
+                        Ok(headers) => (authorized, headers),
                         Err(e) => return response_callback(Err(e)),
                     }
                 }
+            };
+            let client = match EventSourceBuilder::new(authorized).headers(headers).build_async(&core.handle()) {
+                Ok(c) => c,
+                Err(e) => return response_callback(Err(describe_stream(&e))),
+            };
+            let response = client
+                .map_err(|e| describe_stream(&e))
+                .filter_map(|event| event.event_type.map(|t| (t, event.data)))
+                .and_then(|(event, data)| -> Result<Option<Outcome<R>>, String> {
+                    if let ("auth_revoked", Some(ref credentials)) = (&event[..], &backend.credentials) {
+                        credentials.invalidate();
+                        return Ok(Some(Outcome::Revoked));
+                    }
+                    data_callback(event, data).map(|r| r.map(Outcome::Response))
+                })
+                .filter_map(|outcome| outcome)
+                .into_future()
+                .map(|(outcome, _)| outcome.ok_or_else(|| String::from("Connection closed")))
+                .map_err(|(e, _)| e);
+
+            match core.run(response.select2(cancelled)) {
+                Ok(Either::A((Ok(Outcome::Revoked), next))) if reconnects < MAX_RECONNECTS => {
+                    log::info("Token revoked, reconnecting with a new one");
+                    reconnects += 1;
+                    cancelled = next;
+                }
+                Ok(Either::A((Ok(Outcome::Revoked), _))) => return response_callback(Err(String::from("Token revoked"))),
+                Ok(Either::A((Ok(Outcome::Response(res)), _))) => return response_callback(Ok(res)),
+                Ok(Either::A((Err(e), _))) | Err(Either::A((e, _))) => return response_callback(Err(e)),
+                // Stopped, or the worker is gone
+                Ok(Either::B(_)) | Err(Either::B(_)) => return,
             }
-            return response_callback(Err(String::from("Connection closed")));
-        }),
-        sender: timeout_sender,
-        cleanup,
-    }
-}
\ No newline at end of file
+        }
+    }))
+}
+
+/// How a stream of `collect_response` ends.
+enum Outcome<R> {
+    Response(R),
+    /// The backend revoked the token, the stream is reopened with a new one.
+    Revoked,
+}
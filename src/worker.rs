@@ -1,37 +1,136 @@
 use std::{
     thread,
-    sync::mpsc,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
 };
 
+use futures::sync::oneshot;
+
+use log;
 use transport::PendingCleanup;
 
-pub enum Message {
-    StopSelf,
-}
+/// How long to wait for workers to close their connections and exit, as the hooks must not leave
+/// threads running in the application.
+pub const STOP_DEADLINE: Duration = Duration::from_secs(2);
 
+/// A thread that listens on the backend until it is stopped.
+///
+/// It is stopped when dropped: it is cancelled, which closes its connection right away, and
+/// joined, and its cleanup runs, all within `STOP_DEADLINE`. Whatever else the thread blocks on
+/// has to end within `STOP_DEADLINE` as well, as it is never left behind.
 pub struct Worker {
-    pub thread: thread::JoinHandle<()>,
-    pub sender: mpsc::Sender<Message>,
+    thread: Option<thread::JoinHandle<()>>,
+    cancel: Option<oneshot::Sender<()>>,
+    /// Disconnected once the thread exits.
+    done: mpsc::Receiver<()>,
     pub cleanup: Option<PendingCleanup>,
 }
 
 impl Worker {
-    fn send(&self, msg: Message) {
-        match self.sender.send(msg) {
-            Ok(_) => (),
-            Err(_) => (),
+    /// Runs `f` on a new thread. The future it is given completes when the worker is cancelled.
+    pub fn spawn<F>(cleanup: Option<PendingCleanup>, f: F) -> Worker where
+        F: FnOnce(oneshot::Receiver<()>) + Send + 'static {
+        let (cancel, cancelled) = oneshot::channel();
+        let (done_sender, done) = mpsc::channel();
+        Worker {
+            thread: Some(thread::spawn(move || {
+                let _done = done_sender;
+                f(cancelled)
+            })),
+            cancel: Some(cancel),
+            done,
+            cleanup,
         }
     }
-}
 
-impl Drop for Worker {
-    fn drop(&mut self) {
-        self.send(Message::StopSelf);
+    fn cancel(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            // The thread may have exited already.
+            let _ = cancel.send(());
+        }
+    }
+
+    /// Waits for the thread to exit, which it should by `deadline` once cancelled. It is waited
+    /// for even after that, as it must not outlive the hook, which may unload the module.
+    fn join(&mut self, deadline: Instant) {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if let Err(RecvTimeoutError::Timeout) = self.done.recv_timeout(timeout) {
+            log::warning("Worker did not stop in time, waiting for it");
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::warning("Worker panicked");
+            }
+        }
+    }
+
+    fn clean_up(&mut self, deadline: Instant) {
         if let Some(c) = self.cleanup.take() {
-            match c.cleanup() {
+            match c.cleanup(deadline) {
                 Ok(_) => (),
-                Err(e) => println!("Error while cleaning up: {}", e),
+                Err(e) => log::warning(&format!("Error while cleaning up: {}", e)),
             };
         }
     }
-}
\ No newline at end of file
+}
+
+/// Stops all workers at once, so that they share a single deadline.
+pub fn stop_all(mut workers: Vec<Worker>) {
+    for worker in &mut workers {
+        worker.cancel();
+    }
+    let deadline = Instant::now() + STOP_DEADLINE;
+    for worker in &mut workers {
+        worker.join(deadline);
+    }
+    for worker in &mut workers {
+        worker.clean_up(deadline);
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let deadline = Instant::now() + STOP_DEADLINE;
+        if self.thread.is_some() {
+            self.cancel();
+            self.join(deadline);
+        }
+        self.clean_up(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn stop_cancels_and_joins() {
+        let (sender, receiver) = mpsc::channel();
+        let workers = (0..3).map(|_| {
+            let sender = sender.clone();
+            Worker::spawn(None, move |cancelled| {
+                let _ = cancelled.wait();
+                sender.send(()).unwrap();
+            })
+        }).collect();
+
+        let start = Instant::now();
+        stop_all(workers);
+        assert!(start.elapsed() < STOP_DEADLINE);
+        assert_eq!(receiver.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn late_workers_are_waited_for() {
+        let (sender, receiver) = mpsc::channel();
+        let mut worker = Worker::spawn(None, move |_| {
+            thread::sleep(Duration::from_millis(100));
+            sender.send(()).unwrap();
+        });
+        worker.cancel();
+        worker.join(Instant::now());
+        assert!(worker.thread.is_none());
+        assert_eq!(receiver.try_iter().count(), 1);
+    }
+}